@@ -0,0 +1,519 @@
+//! keylightd-ec-sim - a simulated Framework EC for developing keylightd.
+//!
+//! Run `keylightd --ec-socket <path>` to connect the daemon to it. The simulated state can be
+//! inspected and changed by typing commands on stdin, or by writing them to the control socket
+//! (eg. `echo "lid close" | socat - UNIX-CONNECT:<control path>`). Type `help` for a list.
+//!
+//! Clients connect over a Unix socket, so the simulator only runs on Unix.
+
+#![cfg_attr(windows, allow(dead_code, unused_imports))]
+
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use argh::FromArgs;
+use keylightd::{
+    command::{Cmd, LedId, ResetFlag, ResetFlags},
+    ec::{
+        Backend, EcError,
+        packet::{self, PacketError},
+        sim::{SimState, SimulatedEc},
+    },
+    event::{EventType, HostEvent},
+};
+#[cfg(unix)]
+use {
+    keylightd::ec::socket::{self, MSG_COMMAND, MSG_READ_MEMMAP, MSG_SUBSCRIBE},
+    std::os::unix::net::{UnixListener, UnixStream},
+};
+
+/// keylightd-ec-sim - simulated embedded controller for testing keylightd
+#[derive(Debug, FromArgs)]
+struct Args {
+    /// path of the socket to listen on [default=/tmp/keylightd-ec-sim.sock]
+    #[argh(option, default = "PathBuf::from(\"/tmp/keylightd-ec-sim.sock\")")]
+    socket: PathBuf,
+
+    /// path of a socket that accepts the same commands as stdin
+    #[argh(option)]
+    control: Option<PathBuf>,
+
+    /// initial keyboard backlight brightness in percent [default=50]
+    #[argh(option, default = "50")]
+    percent: u8,
+
+    /// delay every command by this many milliseconds [default=0]
+    #[argh(option, default = "0")]
+    latency: u64,
+
+    /// fail this percentage of commands with EC_RES_BUSY [default=0]
+    #[argh(option, default = "0")]
+    error_rate: u8,
+
+    /// randomly change the backlight or lid state every this many seconds
+    #[argh(option, from_str_fn(parse_interval))]
+    wander: Option<u64>,
+}
+
+/// Parses a number of seconds that has to be at least 1.
+fn parse_interval(value: &str) -> Result<u64, String> {
+    match value.parse() {
+        Ok(0) => Err("interval must be at least 1 second".into()),
+        Ok(secs) => Ok(secs),
+        Err(e) => Err(format!("invalid number: {e}")),
+    }
+}
+
+/// Faults injected into the command stream.
+struct Faults {
+    latency: Duration,
+    /// Percentage of commands that fail with `EC_RES_BUSY`.
+    error_rate: u8,
+    /// Number of upcoming commands that fail with `fail_with`.
+    fail_next: u32,
+    fail_with: EcError,
+    rng: Rng,
+}
+
+#[cfg(unix)]
+struct Sim {
+    ec: SimulatedEc,
+    faults: Mutex<Faults>,
+    /// Event streams of subscribed clients, with their event masks.
+    ///
+    /// The streams are non-blocking, so that a client that stopped reading events can't hold up
+    /// everyone else.
+    subscribers: Mutex<Vec<(UnixStream, u32)>>,
+}
+
+#[cfg(unix)]
+impl Sim {
+    /// Notifies subscribers of a host event.
+    ///
+    /// Clients whose socket buffer is full are dropped rather than waited for.
+    fn raise(&self, event: HostEvent) {
+        log::info!("raising host event {:?}", event);
+        let frame = socket::encode_event(
+            EventType::HOST_EVENT.code(),
+            &(event.mask() as u32).to_le_bytes(),
+        );
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain_mut(|(stream, mask)| {
+                if *mask & EventType::HOST_EVENT.mask() == 0 {
+                    return true;
+                }
+                match stream.write_all(&frame) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        log::warn!("dropping event subscriber: {}", e);
+                        false
+                    }
+                }
+            });
+    }
+
+    fn set_lid(&self, open: bool) {
+        self.ec.update(|s| s.lid_open = open);
+        self.raise(if open {
+            HostEvent::LID_OPEN
+        } else {
+            HostEvent::LID_CLOSED
+        });
+    }
+
+    /// Handles a command that changes or reports the simulated state.
+    fn control(&self, line: &str) -> Result<String, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let arg = |i: usize| words.get(i).copied().ok_or("missing argument");
+        let num = |i: usize| -> Result<u64, String> {
+            arg(i)?.parse().map_err(|e| format!("invalid number: {e}"))
+        };
+
+        match words.first().copied().unwrap_or("") {
+            "" => return Ok(String::new()),
+            "help" => return Ok(HELP.trim().to_string()),
+            "state" => return Ok(describe(&self.ec.state())),
+            "backlight" => {
+                let percent = num(1)?.min(100) as u8;
+                self.ec.update(|s| s.set_percent(percent));
+            }
+            "lid" => match arg(1)? {
+                "open" => self.set_lid(true),
+                "close" => self.set_lid(false),
+                _ => return Err("expected `open` or `close`".into()),
+            },
+            "ac" => {
+                let present = match arg(1)? {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err("expected `on` or `off`".into()),
+                };
+                self.ec.update(|s| s.ac_present = present);
+                self.raise(if present {
+                    HostEvent::AC_CONNECTED
+                } else {
+                    HostEvent::AC_DISCONNECTED
+                });
+            }
+            "battery" => {
+                let percent = num(1)?.min(100) as u8;
+                self.ec.update(|s| s.battery = percent);
+                self.raise(HostEvent::BATTERY_STATUS);
+            }
+            "lux" => {
+                let lux = num(1)?.min(u16::MAX.into()) as u16;
+                self.ec.update(|s| s.lux = lux);
+            }
+            "latency" => self.faults.lock().unwrap().latency = Duration::from_millis(num(1)?),
+            "error-rate" => self.faults.lock().unwrap().error_rate = num(1)?.min(100) as u8,
+            "fail" => {
+                let count = num(1)? as u32;
+                let error = match words.get(2) {
+                    Some(name) => parse_error(name)?,
+                    None => EcError::Busy,
+                };
+                let mut faults = self.faults.lock().unwrap();
+                faults.fail_next = count;
+                faults.fail_with = error;
+            }
+            "reset" => {
+                let flag = match words.get(1) {
+                    Some(name) => parse_reset_flag(name)?,
+                    None => ResetFlag::SOFT,
+                };
+                log::info!("EC reset ({:?})", flag);
+                self.ec.reset(ResetFlags(flag.mask()));
+            }
+            cmd => return Err(format!("unknown command `{cmd}`, try `help`")),
+        }
+        Ok("ok".into())
+    }
+
+    /// Serves a client connection until it is closed.
+    fn serve(&self, mut stream: UnixStream) -> io::Result<()> {
+        loop {
+            let mut msg = [0];
+            match stream.read_exact(&mut msg) {
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                res => res?,
+            }
+            match msg[0] {
+                MSG_COMMAND => {
+                    let request = socket::read_request(&mut stream)?;
+                    stream.write_all(&self.command(&request))?;
+                }
+                MSG_READ_MEMMAP => {
+                    let mut req = [0; 2];
+                    stream.read_exact(&mut req)?;
+                    let mut buf = vec![0; usize::from(req[1])];
+                    let len = self.ec.read_memmap(req[0], &mut buf)?;
+                    stream.write_all(&[len as u8])?;
+                    stream.write_all(&buf[..len])?;
+                }
+                MSG_SUBSCRIBE => {
+                    let mut mask = [0; 4];
+                    stream.read_exact(&mut mask)?;
+                    let mask = u32::from_le_bytes(mask);
+                    stream.write_all(&[0])?;
+                    stream.set_nonblocking(true)?;
+                    log::info!("client subscribed to events {:#x}", mask);
+                    self.subscribers.lock().unwrap().push((stream, mask));
+                    return Ok(());
+                }
+                other => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unknown message type {other}"),
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Runs a command packet through the simulated EC and returns the response packet.
+    fn command(&self, request: &[u8]) -> Vec<u8> {
+        let (header, data) = match packet::decode_request(request) {
+            Ok(req) => req,
+            Err(e) => {
+                log::warn!("invalid request packet: {}", e);
+                let error = match e {
+                    PacketError::BadChecksum => EcError::InvalidChecksum,
+                    _ => EcError::InvalidHeader,
+                };
+                return packet::encode_response(error.code() as u16, &[]);
+            }
+        };
+
+        // Don't hold the lock while sleeping, so that other clients and the control socket
+        // aren't delayed along with this command.
+        let (latency, injected) = {
+            let mut faults = self.faults.lock().unwrap();
+            let injected = if faults.fail_next > 0 {
+                faults.fail_next -= 1;
+                Some(faults.fail_with)
+            } else if faults.rng.percent() < faults.error_rate {
+                Some(EcError::Busy)
+            } else {
+                None
+            };
+            (faults.latency, injected)
+        };
+        thread::sleep(latency);
+        if let Some(e) = injected {
+            log::info!("injecting {} into command {:#06x}", e, header.command);
+            return packet::encode_response(e.code() as u16, &[]);
+        }
+
+        let before = self.ec.state();
+        let mut response = [0; 0x100 - 8];
+        let reply = match self.ec.command(
+            header.command.into(),
+            header.command_version.into(),
+            data,
+            &mut response,
+        ) {
+            Ok(reply) => reply,
+            Err(e) => {
+                log::error!("simulated EC failed: {}", e);
+                return packet::encode_response(EcError::Error.code() as u16, &[]);
+            }
+        };
+        log::debug!(
+            "command {:#06x} v{} -> {}",
+            header.command,
+            header.command_version,
+            match EcError::from_code(reply.result) {
+                Some(e) => e.to_string(),
+                None => "EC_RES_SUCCESS".into(),
+            }
+        );
+
+        let after = self.ec.state();
+        if header.command == Cmd::SetKeyboardBacklight as u16 && before.percent != after.percent {
+            log::info!("keyboard backlight: {}%", after.percent);
+        }
+        if header.command == Cmd::PwmSetDuty as u16 && before.duty() != after.duty() {
+            log::info!("keyboard backlight: duty {}", after.duty());
+        }
+        if header.command == Cmd::LedControl as u16 {
+            let changed = before.leds.iter().zip(&after.leds);
+            for (i, (old, new)) in changed.enumerate() {
+                if old.auto != new.auto || old.brightness != new.brightness {
+                    log::info!("LED {}: {}", i, describe_led(new));
+                }
+            }
+        }
+
+        packet::encode_response(reply.result as u16, &response[..reply.len])
+    }
+}
+
+const HELP: &str = "
+state                 print the simulated state
+backlight <percent>   change the keyboard backlight, like Fn+Space does
+lid open|close        open or close the lid
+ac on|off             connect or disconnect the charger
+battery <percent>     set the battery charge
+lux <lux>             set the ambient light sensor reading
+latency <ms>          delay every command
+error-rate <percent>  fail this percentage of commands with EC_RES_BUSY
+fail <n> [error]      fail the next n commands (default error: busy)
+reset [flag]          reboot the EC, losing its settings (default flag: soft)
+";
+
+fn describe(state: &SimState) -> String {
+    let mut out = format!(
+        "backlight: {}% ({})\nlid: {}\nac: {}\nbattery: {}%\nlux: {}\nuptime: {}s ({:?})",
+        state.percent,
+        if state.enabled { "enabled" } else { "disabled" },
+        if state.lid_open { "open" } else { "closed" },
+        if state.ac_present { "on" } else { "off" },
+        state.battery,
+        state.lux,
+        state.booted.elapsed().as_secs(),
+        state.reset_flags,
+    );
+    for i in 0..LedId::COUNT {
+        out += &format!("\nLED {}: {}", i, describe_led(&state.leds[i]));
+    }
+    out
+}
+
+fn describe_led(led: &keylightd::ec::sim::LedState) -> String {
+    if led.auto {
+        "auto".into()
+    } else {
+        format!("{:?}", led.brightness)
+    }
+}
+
+/// Parses an error code, either as a number or as an `EC_RES_*` name (with or without prefix).
+fn parse_error(s: &str) -> Result<EcError, String> {
+    let code = match s.parse::<u32>() {
+        Ok(code) => code,
+        Err(_) => (1..=20)
+            .find(|&code| {
+                EcError::from_code(code).is_some_and(|e| {
+                    let name = e.name();
+                    name.eq_ignore_ascii_case(s)
+                        || name
+                            .strip_prefix("EC_RES_")
+                            .is_some_and(|n| n.eq_ignore_ascii_case(s))
+                })
+            })
+            .ok_or_else(|| format!("unknown error `{s}`"))?,
+    };
+    EcError::from_code(code).ok_or_else(|| format!("{code} is not an error code"))
+}
+
+/// Parses an `EC_RESET_FLAG_*` name (with or without prefix).
+fn parse_reset_flag(s: &str) -> Result<ResetFlag, String> {
+    let s = s.strip_prefix("EC_RESET_FLAG_").unwrap_or(s);
+    ResetFlags(u32::MAX)
+        .iter()
+        .find(|flag| flag.name().is_some_and(|name| name.eq_ignore_ascii_case(s)))
+        .ok_or_else(|| format!("unknown reset flag `{s}`"))
+}
+
+/// Minimal xorshift generator, which is plenty for fault injection.
+struct Rng(u64);
+
+impl Rng {
+    fn new() -> Self {
+        let seed = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        Self(seed | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn percent(&mut self) -> u8 {
+        (self.next() % 100) as u8
+    }
+}
+
+#[cfg(unix)]
+fn listen(path: &Path) -> io::Result<UnixListener> {
+    // Remove a stale socket from a previous run.
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    UnixListener::bind(path)
+}
+
+/// Reads control commands from `input` and writes the replies to `output`.
+#[cfg(unix)]
+fn run_control(sim: &Sim, input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+    for line in input.lines() {
+        let reply = match sim.control(&line?) {
+            Ok(reply) => reply,
+            Err(e) => format!("error: {e}"),
+        };
+        if !reply.is_empty() {
+            writeln!(output, "{reply}")?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn wander(sim: &Sim, interval: Duration) {
+    let mut rng = Rng::new();
+    loop {
+        thread::sleep(interval);
+        match rng.next() % 3 {
+            0 => sim.set_lid(!sim.ec.state().lid_open),
+            _ => {
+                let percent = [0, 20, 50, 100][(rng.next() % 4) as usize];
+                log::info!("user changed keyboard backlight to {}%", percent);
+                sim.ec.update(|s| s.set_percent(percent));
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn main() -> anyhow::Result<()> {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .parse_default_env()
+        .init();
+
+    let args: Args = argh::from_env();
+
+    let sim = Arc::new(Sim {
+        ec: SimulatedEc::new(SimState {
+            percent: args.percent.min(100),
+            enabled: args.percent > 0,
+            ..SimState::default()
+        }),
+        faults: Mutex::new(Faults {
+            latency: Duration::from_millis(args.latency),
+            error_rate: args.error_rate.min(100),
+            fail_next: 0,
+            fail_with: EcError::Busy,
+            rng: Rng::new(),
+        }),
+        subscribers: Mutex::new(Vec::new()),
+    });
+
+    let listener = listen(&args.socket)?;
+    log::info!("listening on {}", args.socket.display());
+
+    if let Some(path) = &args.control {
+        let control = listen(path)?;
+        log::info!("control socket at {}", path.display());
+        let sim = sim.clone();
+        thread::spawn(move || {
+            for stream in control.incoming().flatten() {
+                let sim = sim.clone();
+                thread::spawn(move || {
+                    let input = BufReader::new(stream.try_clone()?);
+                    run_control(&sim, input, stream)
+                });
+            }
+        });
+    }
+
+    if let Some(secs) = args.wander {
+        let sim = sim.clone();
+        thread::spawn(move || wander(&sim, Duration::from_secs(secs)));
+    }
+
+    {
+        let sim = sim.clone();
+        thread::spawn(move || run_control(&sim, io::stdin().lock(), io::stdout()));
+    }
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        log::info!("client connected");
+        let sim = sim.clone();
+        thread::spawn(move || {
+            if let Err(e) = sim.serve(stream) {
+                log::warn!("client error: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn main() -> anyhow::Result<()> {
+    anyhow::bail!("keylightd-ec-sim is only supported on Unix")
+}
@@ -1,13 +1,18 @@
 //! Commands for the Embedded Controller.
 //!
-//! Reference: https://github.com/FrameworkComputer/EmbeddedController/blob/hx20-hx30/include/ec_commands.h
+//! Reference: <https://github.com/FrameworkComputer/EmbeddedController/blob/hx20-hx30/include/ec_commands.h>
 //!
 //! (command IDs begin with `EC_CMD_`)
 
-#![allow(dead_code)]
+use std::{fmt, mem::size_of};
 
 use bytemuck::{NoUninit, Pod, Zeroable};
 
+use crate::{
+    ec::{EmbeddedController, Error},
+    util::str_field,
+};
+
 /// Trait implemented by Embedded Controller commands.
 pub trait Command: NoUninit {
     /// The command ID.
@@ -20,6 +25,33 @@ pub trait Command: NoUninit {
 
     /// The associated response type.
     type Response: Pod;
+
+    /// The minimum number of response bytes the EC has to send.
+    ///
+    /// Responses shorter than this are rejected. Commands whose response has grown over time can
+    /// lower this to accept replies from older firmware, in which case the missing trailing bytes
+    /// of the response are zero.
+    const MIN_RESPONSE_SIZE: usize = size_of::<Self::Response>();
+}
+
+/// A command that comes in several versions with different encodings.
+///
+/// [`EmbeddedController::command_versioned`] asks the EC which versions of the command it supports
+/// and sends it in the highest version both sides understand.
+pub trait Versioned {
+    /// The command ID, shared by all versions.
+    const CMD: Cmd;
+
+    /// Bitmask of the versions this type can be encoded as (bit N means version N).
+    const VERSIONS: u32;
+
+    /// The response, in a form that doesn't depend on the version used.
+    type Output;
+
+    /// Sends the command to `ec`, encoded as `version`.
+    ///
+    /// `version` is always one of the versions in [`Versioned::VERSIONS`].
+    fn send(&self, ec: &EmbeddedController, version: u32) -> Result<Self::Output, Error>;
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -28,23 +60,55 @@ pub enum Cmd {
     ProtoVersion = 0x0000,
     Hello = 0x0001,
     GetVersion = 0x0002,
+    GetBuildInfo = 0x0004,
+    GetChipInfo = 0x0005,
+    GetCmdVersions = 0x0008,
+    GetProtocolInfo = 0x000B,
+    GetFeatures = 0x000D,
     // ...
     GetKeyboardBacklight = 0x0022,
     SetKeyboardBacklight = 0x0023,
+    PwmSetDuty = 0x0025,
+    PwmGetDuty = 0x0026,
     LedControl = 0x0029,
+    // ...
+    GetUptimeInfo = 0x0121,
+}
+
+impl Cmd {
+    /// Looks up a command by its ID, returning `None` for commands keylightd doesn't know.
+    pub fn from_code(code: u32) -> Option<Self> {
+        Some(match code {
+            0x0000 => Self::ProtoVersion,
+            0x0001 => Self::Hello,
+            0x0002 => Self::GetVersion,
+            0x0004 => Self::GetBuildInfo,
+            0x0005 => Self::GetChipInfo,
+            0x0008 => Self::GetCmdVersions,
+            0x000B => Self::GetProtocolInfo,
+            0x000D => Self::GetFeatures,
+            0x0022 => Self::GetKeyboardBacklight,
+            0x0023 => Self::SetKeyboardBacklight,
+            0x0025 => Self::PwmSetDuty,
+            0x0026 => Self::PwmGetDuty,
+            0x0029 => Self::LedControl,
+            0x0121 => Self::GetUptimeInfo,
+            _ => return None,
+        })
+    }
 }
 
 //////////////////////////////////
 // Hello
 //////////////////////////////////
 
-#[derive(Clone, Copy, NoUninit)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
 #[repr(C)]
 pub struct Hello {
     pub in_data: u32,
 }
 
-#[derive(Clone, Copy, Pod, Zeroable)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
 #[repr(C)]
 pub struct HelloResponse {
     pub out_data: u32,
@@ -63,13 +127,94 @@ impl Command for Hello {
 #[repr(C)]
 pub struct GetVersion;
 
-#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[derive(Clone, Copy, Pod, Zeroable)]
 #[repr(C)]
 pub struct GetVersionResponse {
-    version_string_ro: [u8; 32],
-    version_string_rw: [u8; 32],
-    reserved: [u8; 32],
-    current_image: u32,
+    pub(crate) version_string_ro: [u8; 32],
+    pub(crate) version_string_rw: [u8; 32],
+    pub(crate) reserved: [u8; 32],
+    pub(crate) current_image: u32,
+}
+
+impl GetVersionResponse {
+    /// Returns the version of the read-only firmware image.
+    pub fn ro_version(&self) -> &str {
+        str_field(&self.version_string_ro)
+    }
+
+    /// Returns the version of the read-write firmware image.
+    pub fn rw_version(&self) -> &str {
+        str_field(&self.version_string_rw)
+    }
+
+    /// Returns which firmware image the EC is running.
+    pub fn current_image(&self) -> EcImage {
+        EcImage(self.current_image)
+    }
+
+    /// Returns the version of the firmware image the EC is running.
+    pub fn current_version(&self) -> &str {
+        match self.current_image() {
+            EcImage::RO | EcImage::RO_B => self.ro_version(),
+            _ => self.rw_version(),
+        }
+    }
+}
+
+impl fmt::Debug for GetVersionResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GetVersionResponse")
+            .field("ro_version", &self.ro_version())
+            .field("rw_version", &self.rw_version())
+            .field("current_image", &self.current_image())
+            .finish()
+    }
+}
+
+/// A firmware image of the EC (`EC_IMAGE_*`).
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct EcImage(u32);
+
+impl EcImage {
+    pub const UNKNOWN: Self = Self(0);
+    /// The read-only image, which the EC boots into first.
+    pub const RO: Self = Self(1);
+    /// The read-write image, which is the one that gets updated.
+    pub const RW: Self = Self(2);
+    pub const RO_B: Self = Self(3);
+    pub const RW_B: Self = Self(4);
+
+    /// Returns the raw image number.
+    pub fn code(self) -> u32 {
+        self.0
+    }
+
+    /// Returns the name of the image, without the `EC_IMAGE_` prefix.
+    pub fn name(self) -> Option<&'static str> {
+        Some(match self {
+            Self::UNKNOWN => "UNKNOWN",
+            Self::RO => "RO",
+            Self::RW => "RW",
+            Self::RO_B => "RO_B",
+            Self::RW_B => "RW_B",
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Debug for EcImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "EcImage({})", self.0),
+        }
+    }
+}
+
+impl fmt::Display for EcImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
 }
 
 impl Command for GetVersion {
@@ -77,6 +222,407 @@ impl Command for GetVersion {
     type Response = GetVersionResponse;
 }
 
+/// Version 1 of [`GetVersion`], which also returns the ChromeOS firmware IDs.
+#[derive(Clone, Copy, NoUninit)]
+#[repr(C)]
+pub struct GetVersionV1;
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct GetVersionV1Response {
+    pub(crate) version_string_ro: [u8; 32],
+    pub(crate) version_string_rw: [u8; 32],
+    pub(crate) cros_fwid_ro: [u8; 32],
+    pub(crate) current_image: u32,
+    pub(crate) cros_fwid_rw: [u8; 32],
+}
+
+impl GetVersionV1Response {
+    /// Returns the version of the read-only firmware image.
+    pub fn ro_version(&self) -> &str {
+        str_field(&self.version_string_ro)
+    }
+
+    /// Returns the version of the read-write firmware image.
+    pub fn rw_version(&self) -> &str {
+        str_field(&self.version_string_rw)
+    }
+
+    /// Returns the ChromeOS firmware ID of the read-only image (empty for version 0 responses).
+    pub fn ro_fwid(&self) -> &str {
+        str_field(&self.cros_fwid_ro)
+    }
+
+    /// Returns the ChromeOS firmware ID of the read-write image (empty for version 0 responses).
+    pub fn rw_fwid(&self) -> &str {
+        str_field(&self.cros_fwid_rw)
+    }
+
+    /// Returns which firmware image the EC is running.
+    pub fn current_image(&self) -> EcImage {
+        EcImage(self.current_image)
+    }
+
+    /// Returns the version of the firmware image the EC is running.
+    pub fn current_version(&self) -> &str {
+        match self.current_image() {
+            EcImage::RO | EcImage::RO_B => self.ro_version(),
+            _ => self.rw_version(),
+        }
+    }
+}
+
+impl fmt::Debug for GetVersionV1Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GetVersionV1Response")
+            .field("ro_version", &self.ro_version())
+            .field("rw_version", &self.rw_version())
+            .field("ro_fwid", &self.ro_fwid())
+            .field("rw_fwid", &self.rw_fwid())
+            .field("current_image", &self.current_image())
+            .finish()
+    }
+}
+
+impl Command for GetVersionV1 {
+    const CMD: Cmd = Cmd::GetVersion;
+    const VERSION: u32 = 1;
+    type Response = GetVersionV1Response;
+}
+
+impl From<GetVersionResponse> for GetVersionV1Response {
+    fn from(resp: GetVersionResponse) -> Self {
+        Self {
+            version_string_ro: resp.version_string_ro,
+            version_string_rw: resp.version_string_rw,
+            cros_fwid_ro: [0; 32],
+            current_image: resp.current_image,
+            cros_fwid_rw: [0; 32],
+        }
+    }
+}
+
+impl Versioned for GetVersion {
+    const CMD: Cmd = Cmd::GetVersion;
+    const VERSIONS: u32 = 0b11;
+    type Output = GetVersionV1Response;
+
+    fn send(&self, ec: &EmbeddedController, version: u32) -> Result<Self::Output, Error> {
+        match version {
+            0 => ec.command(GetVersion).map(Into::into),
+            _ => ec.command(GetVersionV1),
+        }
+    }
+}
+
+//////////////////////////////////
+// GetBuildInfo
+//////////////////////////////////
+
+/// Queries the EC firmware's build information (a free-form string with the build date, builder
+/// and version).
+#[derive(Clone, Copy, NoUninit)]
+#[repr(C)]
+pub struct GetBuildInfo;
+
+impl Versioned for GetBuildInfo {
+    const CMD: Cmd = Cmd::GetBuildInfo;
+    const VERSIONS: u32 = 0b1;
+    type Output = String;
+
+    fn send(&self, ec: &EmbeddedController, version: u32) -> Result<Self::Output, Error> {
+        // The string fills as much of the response as the EC wants, up to its maximum size.
+        let max = ec.protocol_info().max_response;
+        let resp = ec.command_vec(Self::CMD as u32, version, &[], max)?;
+        Ok(str_field(&resp).to_string())
+    }
+}
+
+//////////////////////////////////
+// GetChipInfo
+//////////////////////////////////
+
+#[derive(Clone, Copy, NoUninit)]
+#[repr(C)]
+pub struct GetChipInfo;
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct GetChipInfoResponse {
+    pub(crate) vendor: [u8; 32],
+    pub(crate) name: [u8; 32],
+    pub(crate) revision: [u8; 32],
+}
+
+impl GetChipInfoResponse {
+    /// Returns the vendor of the EC chip, eg. `Microchip`.
+    pub fn vendor(&self) -> &str {
+        str_field(&self.vendor)
+    }
+
+    /// Returns the name of the EC chip, eg. `MEC1521H-B0-I/SZ`.
+    pub fn name(&self) -> &str {
+        str_field(&self.name)
+    }
+
+    pub fn revision(&self) -> &str {
+        str_field(&self.revision)
+    }
+}
+
+impl fmt::Debug for GetChipInfoResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GetChipInfoResponse")
+            .field("vendor", &self.vendor())
+            .field("name", &self.name())
+            .field("revision", &self.revision())
+            .finish()
+    }
+}
+
+impl Command for GetChipInfo {
+    const CMD: Cmd = Cmd::GetChipInfo;
+    type Response = GetChipInfoResponse;
+}
+
+//////////////////////////////////
+// GetCmdVersions
+//////////////////////////////////
+
+/// Queries the supported versions of a command.
+///
+/// Version 0 of this command only supports command IDs up to `0xff`, see [`GetCmdVersionsV1`].
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct GetCmdVersions {
+    pub cmd: u8,
+}
+
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct GetCmdVersionsResponse {
+    /// Bitmask of supported versions (bit N means version N).
+    pub version_mask: u32,
+}
+
+impl Command for GetCmdVersions {
+    const CMD: Cmd = Cmd::GetCmdVersions;
+    type Response = GetCmdVersionsResponse;
+}
+
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct GetCmdVersionsV1 {
+    pub cmd: u16,
+}
+
+impl Command for GetCmdVersionsV1 {
+    const CMD: Cmd = Cmd::GetCmdVersions;
+    const VERSION: u32 = 1;
+    type Response = GetCmdVersionsResponse;
+}
+
+//////////////////////////////////
+// GetProtocolInfo
+//////////////////////////////////
+
+#[derive(Clone, Copy, NoUninit)]
+#[repr(C)]
+pub struct GetProtocolInfo;
+
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct GetProtocolInfoResponse {
+    /// Bitmask of supported host command protocol versions (bit N means version N).
+    pub protocol_versions: u32,
+    /// Maximum request packet size, including the packet header.
+    pub max_request_packet_size: u16,
+    /// Maximum response packet size, including the packet header.
+    pub max_response_packet_size: u16,
+    pub flags: u32,
+}
+
+impl Command for GetProtocolInfo {
+    const CMD: Cmd = Cmd::GetProtocolInfo;
+    type Response = GetProtocolInfoResponse;
+}
+
+//////////////////////////////////
+// GetFeatures
+//////////////////////////////////
+
+#[derive(Clone, Copy, NoUninit)]
+#[repr(C)]
+pub struct GetFeatures;
+
+/// Bitmap of the features supported by the EC.
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct GetFeaturesResponse {
+    pub flags: [u32; 2],
+}
+
+impl GetFeaturesResponse {
+    pub fn contains(&self, feature: Feature) -> bool {
+        let bit = usize::from(feature.0);
+        self.flags
+            .get(bit / 32)
+            .is_some_and(|flags| flags & (1 << (bit % 32)) != 0)
+    }
+
+    pub fn insert(&mut self, feature: Feature) {
+        let bit = usize::from(feature.0);
+        self.flags[bit / 32] |= 1 << (bit % 32);
+    }
+
+    /// Returns an iterator over all supported features.
+    pub fn iter(&self) -> impl Iterator<Item = Feature> + '_ {
+        (0..64).map(Feature).filter(|&f| self.contains(f))
+    }
+}
+
+impl Command for GetFeatures {
+    const CMD: Cmd = Cmd::GetFeatures;
+    type Response = GetFeaturesResponse;
+}
+
+/// An EC feature (`EC_FEATURE_*`).
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct Feature(u8);
+
+impl Feature {
+    /// This image contains a limited set of features. Another image in the RW partition may
+    /// support more features.
+    pub const LIMITED: Self = Self(0);
+    pub const FLASH: Self = Self(1);
+    /// PWM fan control.
+    pub const PWM_FAN: Self = Self(2);
+    /// PWM keyboard backlight.
+    pub const PWM_KEYB: Self = Self(3);
+    pub const LIGHTBAR: Self = Self(4);
+    /// LEDs controllable via `EC_CMD_LED_CONTROL`.
+    pub const LED: Self = Self(5);
+    /// Motion sensors, including the ambient light sensor.
+    pub const MOTION_SENSE: Self = Self(6);
+    /// Keyboard (MKBP).
+    pub const KEYB: Self = Self(7);
+    pub const PSTORE: Self = Self(8);
+    pub const PORT80: Self = Self(9);
+    pub const THERMAL: Self = Self(10);
+    pub const BKLIGHT_SWITCH: Self = Self(11);
+    pub const WIFI_SWITCH: Self = Self(12);
+    /// Host events, through the MKBP interface.
+    pub const HOST_EVENTS: Self = Self(13);
+    pub const GPIO: Self = Self(14);
+    pub const I2C: Self = Self(15);
+    pub const CHARGER: Self = Self(16);
+    pub const BATTERY: Self = Self(17);
+    pub const SMART_BATTERY: Self = Self(18);
+    pub const HANG_DETECT: Self = Self(19);
+    pub const PMU: Self = Self(20);
+    pub const SUB_MCU: Self = Self(21);
+    /// USB Power Delivery.
+    pub const USB_PD: Self = Self(22);
+    pub const USB_MUX: Self = Self(23);
+    pub const MOTION_SENSE_FIFO: Self = Self(24);
+    pub const VSTORE: Self = Self(25);
+    pub const USBC_SS_MUX_VIRTUAL: Self = Self(26);
+    pub const RTC: Self = Self(27);
+    pub const FINGERPRINT: Self = Self(28);
+    pub const TOUCHPAD: Self = Self(29);
+    pub const RWSIG: Self = Self(30);
+    pub const DEVICE_EVENT: Self = Self(31);
+    pub const UNIFIED_WAKE_MASKS: Self = Self(32);
+    pub const HOST_EVENT64: Self = Self(33);
+    pub const EXEC_IN_RAM: Self = Self(34);
+    pub const CEC: Self = Self(35);
+    pub const MOTION_SENSE_TIGHT_TIMESTAMPS: Self = Self(36);
+    pub const REFINED_TABLET_MODE_HYSTERESIS: Self = Self(37);
+    pub const EFS2: Self = Self(38);
+    pub const SCP: Self = Self(39);
+    pub const ISH: Self = Self(40);
+    pub const TYPEC_CMD: Self = Self(41);
+    pub const TYPEC_REQUIRE_AP_MODE_ENTRY: Self = Self(42);
+    pub const TYPEC_MUX_REQUIRE_AP_ACK: Self = Self(43);
+    pub const S4_RESIDENCY: Self = Self(44);
+    pub const TYPEC_AP_MUX_SET: Self = Self(45);
+    pub const TYPEC_AP_VDM_SEND: Self = Self(46);
+    pub const SYSTEM_SAFE_MODE: Self = Self(47);
+    pub const ASSERT_REBOOTS: Self = Self(48);
+    pub const TOKENIZED_LOGGING: Self = Self(49);
+    pub const AMD_STB_DUMP: Self = Self(50);
+    pub const MEMORY_DUMP: Self = Self(51);
+
+    /// Returns the name of the feature, without the `EC_FEATURE_` prefix.
+    pub fn name(self) -> Option<&'static str> {
+        Some(match self {
+            Self::LIMITED => "LIMITED",
+            Self::FLASH => "FLASH",
+            Self::PWM_FAN => "PWM_FAN",
+            Self::PWM_KEYB => "PWM_KEYB",
+            Self::LIGHTBAR => "LIGHTBAR",
+            Self::LED => "LED",
+            Self::MOTION_SENSE => "MOTION_SENSE",
+            Self::KEYB => "KEYB",
+            Self::PSTORE => "PSTORE",
+            Self::PORT80 => "PORT80",
+            Self::THERMAL => "THERMAL",
+            Self::BKLIGHT_SWITCH => "BKLIGHT_SWITCH",
+            Self::WIFI_SWITCH => "WIFI_SWITCH",
+            Self::HOST_EVENTS => "HOST_EVENTS",
+            Self::GPIO => "GPIO",
+            Self::I2C => "I2C",
+            Self::CHARGER => "CHARGER",
+            Self::BATTERY => "BATTERY",
+            Self::SMART_BATTERY => "SMART_BATTERY",
+            Self::HANG_DETECT => "HANG_DETECT",
+            Self::PMU => "PMU",
+            Self::SUB_MCU => "SUB_MCU",
+            Self::USB_PD => "USB_PD",
+            Self::USB_MUX => "USB_MUX",
+            Self::MOTION_SENSE_FIFO => "MOTION_SENSE_FIFO",
+            Self::VSTORE => "VSTORE",
+            Self::USBC_SS_MUX_VIRTUAL => "USBC_SS_MUX_VIRTUAL",
+            Self::RTC => "RTC",
+            Self::FINGERPRINT => "FINGERPRINT",
+            Self::TOUCHPAD => "TOUCHPAD",
+            Self::RWSIG => "RWSIG",
+            Self::DEVICE_EVENT => "DEVICE_EVENT",
+            Self::UNIFIED_WAKE_MASKS => "UNIFIED_WAKE_MASKS",
+            Self::HOST_EVENT64 => "HOST_EVENT64",
+            Self::EXEC_IN_RAM => "EXEC_IN_RAM",
+            Self::CEC => "CEC",
+            Self::MOTION_SENSE_TIGHT_TIMESTAMPS => "MOTION_SENSE_TIGHT_TIMESTAMPS",
+            Self::REFINED_TABLET_MODE_HYSTERESIS => "REFINED_TABLET_MODE_HYSTERESIS",
+            Self::EFS2 => "EFS2",
+            Self::SCP => "SCP",
+            Self::ISH => "ISH",
+            Self::TYPEC_CMD => "TYPEC_CMD",
+            Self::TYPEC_REQUIRE_AP_MODE_ENTRY => "TYPEC_REQUIRE_AP_MODE_ENTRY",
+            Self::TYPEC_MUX_REQUIRE_AP_ACK => "TYPEC_MUX_REQUIRE_AP_ACK",
+            Self::S4_RESIDENCY => "S4_RESIDENCY",
+            Self::TYPEC_AP_MUX_SET => "TYPEC_AP_MUX_SET",
+            Self::TYPEC_AP_VDM_SEND => "TYPEC_AP_VDM_SEND",
+            Self::SYSTEM_SAFE_MODE => "SYSTEM_SAFE_MODE",
+            Self::ASSERT_REBOOTS => "ASSERT_REBOOTS",
+            Self::TOKENIZED_LOGGING => "TOKENIZED_LOGGING",
+            Self::AMD_STB_DUMP => "AMD_STB_DUMP",
+            Self::MEMORY_DUMP => "MEMORY_DUMP",
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Debug for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "Feature({})", self.0),
+        }
+    }
+}
+
 //////////////////////////////////
 // GetKeyboardBacklight
 //////////////////////////////////
@@ -101,7 +647,7 @@ impl Command for GetKeyboardBacklight {
 // SetKeyboardBacklight
 //////////////////////////////////
 
-#[derive(Clone, Copy, NoUninit)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
 #[repr(C)]
 pub struct SetKeyboardBacklight {
     pub percent: u8,
@@ -116,6 +662,92 @@ impl Command for SetKeyboardBacklight {
     type Response = SetKeyboardBacklightResponse;
 }
 
+//////////////////////////////////
+// PwmSetDuty
+//////////////////////////////////
+
+/// The largest PWM duty cycle (always on).
+pub const PWM_MAX_DUTY: u16 = 0xffff;
+
+/// Sets the duty cycle of a PWM output.
+///
+/// Unlike [`SetKeyboardBacklight`], this allows setting the keyboard backlight in steps of
+/// 1/65535 rather than 1/100.
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct PwmSetDuty {
+    /// Duty cycle, from 0 to [`PWM_MAX_DUTY`].
+    pub duty: u16,
+    pub pwm_type: PwmType,
+    /// Index of the PWM output, only used with [`PwmType::GENERIC`].
+    pub index: u8,
+}
+
+impl PwmSetDuty {
+    pub fn keyboard_backlight(duty: u16) -> Self {
+        Self {
+            duty,
+            pwm_type: PwmType::KB_LIGHT,
+            index: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct PwmSetDutyResponse;
+
+impl Command for PwmSetDuty {
+    const CMD: Cmd = Cmd::PwmSetDuty;
+    type Response = PwmSetDutyResponse;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Pod, Zeroable)]
+#[repr(transparent)]
+pub struct PwmType(u8);
+
+impl PwmType {
+    /// A PWM output selected by index.
+    pub const GENERIC: Self = Self(0);
+    /// The keyboard backlight.
+    pub const KB_LIGHT: Self = Self(1);
+    /// The display backlight.
+    pub const DISPLAY_LIGHT: Self = Self(2);
+}
+
+//////////////////////////////////
+// PwmGetDuty
+//////////////////////////////////
+
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct PwmGetDuty {
+    pub pwm_type: PwmType,
+    /// Index of the PWM output, only used with [`PwmType::GENERIC`].
+    pub index: u8,
+}
+
+impl PwmGetDuty {
+    pub fn keyboard_backlight() -> Self {
+        Self {
+            pwm_type: PwmType::KB_LIGHT,
+            index: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct PwmGetDutyResponse {
+    /// Duty cycle, from 0 to [`PWM_MAX_DUTY`].
+    pub duty: u16,
+}
+
+impl Command for PwmGetDuty {
+    const CMD: Cmd = Cmd::PwmGetDuty;
+    type Response = PwmGetDutyResponse;
+}
+
 //////////////////////////////////
 // LedControl
 //////////////////////////////////
@@ -148,6 +780,11 @@ impl LedId {
     pub const RIGHT: Self = Self(4);
     pub const RECOVERY_HW_REINIT: Self = Self(5);
     pub const SYSRQ_DEBUG: Self = Self(6);
+    pub const COUNT: usize = 7;
+
+    pub fn index(self) -> usize {
+        usize::from(self.0)
+    }
 }
 
 #[derive(Debug, Default, Clone, Copy, Pod, Zeroable)]
@@ -158,8 +795,13 @@ impl LedFlags {
     pub const NONE: Self = Self(0);
     pub const QUERY: Self = Self(1 << 0);
     pub const AUTO: Self = Self(1 << 1);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct LedColor(u8);
 
 impl LedColor {
@@ -172,7 +814,7 @@ impl LedColor {
     pub const COUNT: usize = 6;
 }
 
-#[derive(Debug, Default, Clone, Copy, Pod, Zeroable)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Pod, Zeroable)]
 #[repr(transparent)]
 pub struct LedBrightnesses {
     raw: [u8; LedColor::COUNT],
@@ -187,10 +829,255 @@ impl LedBrightnesses {
         self.raw[usize::from(color.0)] = brightness;
         self
     }
+
+    pub fn get(&self, color: LedColor) -> u8 {
+        self.raw[usize::from(color.0)]
+    }
 }
 
 #[derive(Debug, Default, Clone, Copy, Pod, Zeroable)]
 #[repr(transparent)]
 pub struct LedControlResponse {
-    brightness: LedBrightnesses,
+    pub(crate) brightness: LedBrightnesses,
+}
+
+impl LedControlResponse {
+    /// Returns the brightness range of each color channel of the LED (for [`LedFlags::QUERY`]).
+    pub fn brightness(&self) -> LedBrightnesses {
+        self.brightness
+    }
+}
+
+//////////////////////////////////
+// GetUptimeInfo
+//////////////////////////////////
+
+/// Queries how long the EC has been running, why it was last reset, and the last few times it
+/// reset the host.
+#[derive(Clone, Copy, NoUninit)]
+#[repr(C)]
+pub struct GetUptimeInfo;
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct GetUptimeInfoResponse {
+    /// Milliseconds since the EC booted. Wraps around after about 49 days.
+    pub time_since_ec_boot_ms: u32,
+    /// Number of times the EC reset the AP since it booted.
+    pub ap_resets_since_ec_boot: u32,
+    pub(crate) ec_reset_flags: u32,
+    pub(crate) recent_ap_reset: [ApResetLogEntry; 4],
+}
+
+impl GetUptimeInfoResponse {
+    /// Returns why the EC was last reset.
+    pub fn reset_flags(&self) -> ResetFlags {
+        ResetFlags(self.ec_reset_flags)
+    }
+
+    /// Returns the most recent resets of the AP, skipping unused log slots.
+    pub fn recent_ap_resets(&self) -> impl Iterator<Item = ApResetLogEntry> + '_ {
+        self.recent_ap_reset
+            .iter()
+            .copied()
+            .filter(|entry| entry.reset_time_ms != 0)
+    }
+}
+
+impl fmt::Debug for GetUptimeInfoResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GetUptimeInfoResponse")
+            .field("time_since_ec_boot_ms", &self.time_since_ec_boot_ms)
+            .field("ap_resets_since_ec_boot", &self.ap_resets_since_ec_boot)
+            .field("ec_reset_flags", &self.reset_flags())
+            .field(
+                "recent_ap_reset",
+                &self.recent_ap_resets().collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl Command for GetUptimeInfo {
+    const CMD: Cmd = Cmd::GetUptimeInfo;
+    type Response = GetUptimeInfoResponse;
+}
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct ApResetLogEntry {
+    reset_cause: u16,
+    reserved: u16,
+    /// EC uptime at the time of the reset, in milliseconds.
+    pub reset_time_ms: u32,
+}
+
+impl ApResetLogEntry {
+    pub fn reset_cause(&self) -> ApResetCause {
+        ApResetCause(self.reset_cause)
+    }
+}
+
+impl fmt::Debug for ApResetLogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ApResetLogEntry")
+            .field("reset_cause", &self.reset_cause())
+            .field("reset_time_ms", &self.reset_time_ms)
+            .finish()
+    }
+}
+
+/// Why the EC reset or shut down the AP (`enum chipset_reset_reason` and
+/// `enum chipset_shutdown_reason`).
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ApResetCause(u16);
+
+impl ApResetCause {
+    pub const RESET_UNKNOWN: Self = Self(0);
+    pub const RESET_KB_SYSRESET: Self = Self(1);
+    pub const RESET_KB_WARM_REBOOT: Self = Self(2);
+    pub const RESET_DBG_WARM_REBOOT: Self = Self(3);
+    pub const RESET_AP_REQ: Self = Self(4);
+    pub const RESET_INIT: Self = Self(5);
+    pub const RESET_AP_WATCHDOG: Self = Self(6);
+    pub const SHUTDOWN_POWERFAIL: Self = Self(0x8000);
+    pub const SHUTDOWN_INIT: Self = Self(0x8001);
+    pub const SHUTDOWN_PANIC: Self = Self(0x8002);
+    pub const SHUTDOWN_WAIT: Self = Self(0x8003);
+    pub const SHUTDOWN_G3: Self = Self(0x8004);
+    pub const SHUTDOWN_THERMAL: Self = Self(0x8005);
+    pub const SHUTDOWN_BATTERY_CRIT: Self = Self(0x8006);
+    pub const SHUTDOWN_BATTERY_INHIBIT: Self = Self(0x8007);
+    pub const SHUTDOWN_HOST_CMD: Self = Self(0x8008);
+    pub const SHUTDOWN_CONSOLE_CMD: Self = Self(0x8009);
+    pub const SHUTDOWN_BUTTON: Self = Self(0x800a);
+
+    /// Returns the name of the cause, without the `CHIPSET_` prefix.
+    pub fn name(self) -> Option<&'static str> {
+        Some(match self {
+            Self::RESET_UNKNOWN => "RESET_UNKNOWN",
+            Self::RESET_KB_SYSRESET => "RESET_KB_SYSRESET",
+            Self::RESET_KB_WARM_REBOOT => "RESET_KB_WARM_REBOOT",
+            Self::RESET_DBG_WARM_REBOOT => "RESET_DBG_WARM_REBOOT",
+            Self::RESET_AP_REQ => "RESET_AP_REQ",
+            Self::RESET_INIT => "RESET_INIT",
+            Self::RESET_AP_WATCHDOG => "RESET_AP_WATCHDOG",
+            Self::SHUTDOWN_POWERFAIL => "SHUTDOWN_POWERFAIL",
+            Self::SHUTDOWN_INIT => "SHUTDOWN_INIT",
+            Self::SHUTDOWN_PANIC => "SHUTDOWN_PANIC",
+            Self::SHUTDOWN_WAIT => "SHUTDOWN_WAIT",
+            Self::SHUTDOWN_G3 => "SHUTDOWN_G3",
+            Self::SHUTDOWN_THERMAL => "SHUTDOWN_THERMAL",
+            Self::SHUTDOWN_BATTERY_CRIT => "SHUTDOWN_BATTERY_CRIT",
+            Self::SHUTDOWN_BATTERY_INHIBIT => "SHUTDOWN_BATTERY_INHIBIT",
+            Self::SHUTDOWN_HOST_CMD => "SHUTDOWN_HOST_CMD",
+            Self::SHUTDOWN_CONSOLE_CMD => "SHUTDOWN_CONSOLE_CMD",
+            Self::SHUTDOWN_BUTTON => "SHUTDOWN_BUTTON",
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Debug for ApResetCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "ApResetCause({:#06x})", self.0),
+        }
+    }
+}
+
+/// A reason for the EC's last reset (`EC_RESET_FLAG_*`).
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ResetFlag(u8);
+
+impl ResetFlag {
+    pub const OTHER: Self = Self(0);
+    pub const RESET_PIN: Self = Self(1);
+    pub const BROWNOUT: Self = Self(2);
+    pub const POWER_ON: Self = Self(3);
+    pub const WATCHDOG: Self = Self(4);
+    pub const SOFT: Self = Self(5);
+    pub const HIBERNATE: Self = Self(6);
+    pub const RTC_ALARM: Self = Self(7);
+    pub const WAKE_PIN: Self = Self(8);
+    pub const LOW_BATTERY: Self = Self(9);
+    pub const SYSJUMP: Self = Self(10);
+    pub const HARD: Self = Self(11);
+    pub const AP_OFF: Self = Self(12);
+    pub const PRESERVED: Self = Self(13);
+    pub const USB_RESUME: Self = Self(14);
+    pub const RDD: Self = Self(15);
+    pub const RBOX: Self = Self(16);
+    pub const SECURITY: Self = Self(17);
+    pub const AP_WATCHDOG: Self = Self(18);
+    pub const STAY_IN_RO: Self = Self(19);
+    pub const EFS: Self = Self(20);
+    pub const AP_IDLE: Self = Self(21);
+    pub const INITIAL_PWR: Self = Self(22);
+
+    /// Returns the bit of this flag in a set of reset flags.
+    pub fn mask(self) -> u32 {
+        1 << self.0
+    }
+
+    /// Returns the name of the flag, without the `EC_RESET_FLAG_` prefix.
+    pub fn name(self) -> Option<&'static str> {
+        Some(match self {
+            Self::OTHER => "OTHER",
+            Self::RESET_PIN => "RESET_PIN",
+            Self::BROWNOUT => "BROWNOUT",
+            Self::POWER_ON => "POWER_ON",
+            Self::WATCHDOG => "WATCHDOG",
+            Self::SOFT => "SOFT",
+            Self::HIBERNATE => "HIBERNATE",
+            Self::RTC_ALARM => "RTC_ALARM",
+            Self::WAKE_PIN => "WAKE_PIN",
+            Self::LOW_BATTERY => "LOW_BATTERY",
+            Self::SYSJUMP => "SYSJUMP",
+            Self::HARD => "HARD",
+            Self::AP_OFF => "AP_OFF",
+            Self::PRESERVED => "PRESERVED",
+            Self::USB_RESUME => "USB_RESUME",
+            Self::RDD => "RDD",
+            Self::RBOX => "RBOX",
+            Self::SECURITY => "SECURITY",
+            Self::AP_WATCHDOG => "AP_WATCHDOG",
+            Self::STAY_IN_RO => "STAY_IN_RO",
+            Self::EFS => "EFS",
+            Self::AP_IDLE => "AP_IDLE",
+            Self::INITIAL_PWR => "INITIAL_PWR",
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Debug for ResetFlag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "ResetFlag({})", self.0),
+        }
+    }
+}
+
+/// The set of reasons for the EC's last reset.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResetFlags(pub u32);
+
+impl ResetFlags {
+    pub fn contains(self, flag: ResetFlag) -> bool {
+        self.0 & flag.mask() != 0
+    }
+
+    /// Returns an iterator over the flags in the set.
+    pub fn iter(self) -> impl Iterator<Item = ResetFlag> {
+        (0..32).map(ResetFlag).filter(move |&f| self.contains(f))
+    }
+}
+
+impl fmt::Debug for ResetFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
 }
@@ -1,163 +1,945 @@
+//! Communication with the Embedded Controller.
+
+#[cfg(unix)]
+use std::os::fd::RawFd;
 use std::{
-    fs::File,
-    io,
-    mem::{size_of, size_of_val, MaybeUninit},
+    collections::HashMap,
+    io, mem,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU32, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use bytemuck::Zeroable;
+
+use crate::{
+    command::{
+        self, GetCmdVersions, GetCmdVersionsV1, GetFeatures, GetFeaturesResponse, GetProtocolInfo,
+        GetProtocolInfoResponse, Hello, Versioned,
+    },
+    event::Event,
+    memmap,
 };
 
-use crate::command::{self, Hello};
+#[cfg(feature = "async")]
+pub mod asynchronous;
+mod batch;
+pub mod device;
+mod error;
+#[cfg(unix)]
+mod ioctl;
+#[cfg(unix)]
+pub mod lpc;
+pub mod packet;
+pub mod sim;
+#[cfg(unix)]
+pub mod socket;
+pub mod stats;
+pub mod trace;
+mod worker;
+
+#[cfg(feature = "async")]
+pub use asynchronous::{AsyncEmbeddedController, EcFuture};
+pub use batch::{Batch, BatchReport, StepReport};
+pub use error::{EcError, Error};
+#[cfg(unix)]
+pub use ioctl::Ioctl;
+#[cfg(unix)]
+pub use lpc::Lpc;
+#[cfg(unix)]
+pub use socket::Socket;
+pub use stats::CommandStats;
+pub use trace::{Recorder, Replay};
+use worker::{RunError, Worker};
 
+/// A transport that delivers raw host commands to an Embedded Controller.
+///
+/// [`EmbeddedController`] encodes typed [`command::Command`]s and hands the resulting bytes to its
+/// backend, so anything that can answer host commands (the real EC, or a simulation of one) can be
+/// plugged in.
+///
+/// [`EmbeddedController`] serializes all calls into its backend, so backends only have to be
+/// [`Send`], not [`Sync`].
+pub trait Backend: Send {
+    /// Issues command `command` with version `version` to the EC.
+    ///
+    /// `request` contains the encoded request parameters, and the EC's reply is written to the
+    /// start of `response`. Errors that prevent the command from reaching the EC are returned as
+    /// [`io::Error`]s, errors reported by the EC itself are returned in [`Reply::result`].
+    fn command(
+        &self,
+        command: u32,
+        version: u32,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> io::Result<Reply>;
+
+    /// Reads `buf.len()` bytes from the EC's memory-mapped region, starting at `offset`.
+    ///
+    /// Returns the number of bytes read. Backends without access to the memory-mapped region
+    /// return an error of kind [`io::ErrorKind::Unsupported`].
+    fn read_memmap(&self, offset: u8, buf: &mut [u8]) -> io::Result<usize> {
+        let _ = (offset, buf);
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Selects the MKBP event types that are delivered through [`Backend::read_event`].
+    ///
+    /// `mask` has bit N set for every event type N that should be delivered.
+    fn set_event_mask(&self, mask: u32) -> io::Result<()> {
+        let _ = mask;
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Reads a pending event into `buf` without blocking.
+    ///
+    /// Returns the event's length, or an error of kind [`io::ErrorKind::WouldBlock`] if no event is
+    /// pending.
+    fn read_event(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let _ = buf;
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Returns a file descriptor that becomes readable when an event is pending.
+    #[cfg(unix)]
+    fn event_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+/// Allows keeping a handle to a backend that is owned by an [`EmbeddedController`], for example to
+/// change the state of a [`sim::SimulatedEc`] while the controller is using it.
+impl<B: Backend + Sync + ?Sized> Backend for Arc<B> {
+    fn command(
+        &self,
+        command: u32,
+        version: u32,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> io::Result<Reply> {
+        (**self).command(command, version, request, response)
+    }
+
+    fn read_memmap(&self, offset: u8, buf: &mut [u8]) -> io::Result<usize> {
+        (**self).read_memmap(offset, buf)
+    }
+
+    fn set_event_mask(&self, mask: u32) -> io::Result<()> {
+        (**self).set_event_mask(mask)
+    }
+
+    fn read_event(&self, buf: &mut [u8]) -> io::Result<usize> {
+        (**self).read_event(buf)
+    }
+
+    #[cfg(unix)]
+    fn event_fd(&self) -> Option<RawFd> {
+        (**self).event_fd()
+    }
+}
+
+/// What the EC sent back in response to a command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reply {
+    /// The result code reported by the EC (`EC_RES_*`).
+    pub result: u32,
+    /// The number of response bytes the EC wrote.
+    pub len: usize,
+}
+
+impl Reply {
+    pub fn success(len: usize) -> Self {
+        Self {
+            result: EcError::SUCCESS,
+            len,
+        }
+    }
+
+    pub fn error(e: EcError) -> Self {
+        Self {
+            result: e.code(),
+            len: 0,
+        }
+    }
+}
+
+/// Host command protocol parameters reported by the EC.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum IoctlVersion {
-    V1,
-    V2,
+pub struct ProtocolInfo {
+    /// Bitmask of supported host command protocol versions (bit N means version N).
+    pub versions: u32,
+    /// Maximum size of a request's parameters, in bytes.
+    pub max_request: usize,
+    /// Maximum size of a response's payload, in bytes.
+    pub max_response: usize,
 }
 
+impl ProtocolInfo {
+    /// Size of the `ec_host_request` and `ec_host_response` packet headers, which count towards
+    /// the packet sizes reported by the EC.
+    const PACKET_HEADER_SIZE: usize = std::mem::size_of::<packet::RequestHeader>();
+
+    /// Limits of ECs that predate `EC_CMD_GET_PROTOCOL_INFO` and only speak protocol version 2
+    /// (`EC_PROTO2_MAX_PARAM_SIZE`).
+    pub const V2: Self = Self {
+        versions: 1 << 2,
+        max_request: 0xfc,
+        max_response: 0xfc,
+    };
+
+    /// Extracts the payload limits from the EC's answer to `EC_CMD_GET_PROTOCOL_INFO`.
+    pub fn from_response(resp: &GetProtocolInfoResponse) -> Self {
+        Self {
+            versions: resp.protocol_versions,
+            max_request: usize::from(resp.max_request_packet_size)
+                .saturating_sub(Self::PACKET_HEADER_SIZE),
+            max_response: usize::from(resp.max_response_packet_size)
+                .saturating_sub(Self::PACKET_HEADER_SIZE),
+        }
+    }
+}
+
+/// How [`EmbeddedController`] retries commands that fail with a transient error.
+///
+/// See [`Error::is_transient`] for which errors are retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one.
+    pub attempts: u32,
+    /// Delay before the first retry. It doubles with every further retry.
+    pub initial_backoff: Duration,
+    /// Upper limit for the delay between retries.
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Don't retry failed commands.
+    pub const NONE: Self = Self {
+        attempts: 1,
+        initial_backoff: Duration::ZERO,
+        max_backoff: Duration::ZERO,
+    };
+
+    /// Calls `attempt` until it succeeds, fails with a non-transient error, or the attempts are
+    /// used up.
+    fn run<T>(
+        &self,
+        command: u32,
+        mut attempt: impl FnMut() -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let mut attempts = 1;
+        let mut backoff = self.initial_backoff;
+        loop {
+            match attempt() {
+                Err(e) if e.is_transient() && attempts < self.attempts => {
+                    log::debug!(
+                        "EC command {:#06x} failed ({}), retrying in {:?}",
+                        command,
+                        e,
+                        backoff
+                    );
+                    thread::sleep(backoff);
+                    backoff = backoff.saturating_mul(2).min(self.max_backoff);
+                    attempts += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(50),
+        }
+    }
+}
+
+/// The device node of the main EC, created by the `cros_ec` driver.
+pub const DEVICE_PATH: &str = "/dev/cros_ec";
+
+/// Opens the EC device node at `path` (usually [`DEVICE_PATH`]).
+///
+/// This never falls back to talking to the EC over LPC directly, since that would race with a
+/// `cros_ec` driver that is loaded later. Use [`Lpc::open`] explicitly for that.
+#[cfg(unix)]
+pub fn open_backend(path: &Path) -> io::Result<Box<dyn Backend>> {
+    Ok(Box::new(Ioctl::open(path)?))
+}
+
+#[cfg(windows)]
+pub fn open_backend(path: &Path) -> io::Result<Box<dyn Backend>> {
+    panic!()
+}
+
+type Opener = Arc<dyn Fn() -> io::Result<Box<dyn Backend>> + Send + Sync>;
+
 /// A handle to the system's ChromiumOS Embedded Controller.
 ///
-/// This uses the ioctl interface of `/dev/cros_ec` to issue commands.
+/// Commands are issued through a [`Backend`]. By default, this is the ioctl interface of
+/// `/dev/cros_ec`.
+///
+/// The handle can be shared between threads. Commands are submitted one at a time, and ones that
+/// fail with a transient error are retried according to the [`RetryPolicy`].
+///
+/// The backend runs on a dedicated thread, so that a hung EC transaction can't block callers
+/// forever. Transactions that don't finish within the timeout fail with [`Error::Timeout`].
 pub struct EmbeddedController {
-    fd: File,
-    version: IoctlVersion,
+    worker: Worker,
+    /// Creates a new backend for [`EmbeddedController::reopen`].
+    opener: Option<Opener>,
+    retry: RetryPolicy,
+    timeout: Duration,
+    /// Number of transactions that timed out in a row.
+    timeouts: AtomicU32,
+    protocol: ProtocolInfo,
+    features: Option<GetFeaturesResponse>,
+    /// Cached results of [`EmbeddedController::command_versions`].
+    versions: Mutex<HashMap<u32, Option<u32>>>,
+    /// Latency statistics by command code.
+    stats: Mutex<HashMap<u32, CommandStats>>,
 }
 
 impl EmbeddedController {
-    #[cfg(unix)]
+    /// How long to wait for an EC transaction by default.
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+
+    /// Number of consecutive timeouts after which the EC is considered unhealthy.
+    const UNHEALTHY_AFTER: u32 = 3;
+
+    /// Opens the system's EC with the backend chosen by [`open_backend`].
     pub fn open() -> io::Result<Self> {
+        Self::open_device(DEVICE_PATH)
+    }
+
+    /// Opens the EC behind the device node at `path`, eg. `/dev/cros_fp` for the fingerprint
+    /// sensor's EC.
+    pub fn open_device(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        Self::with_opener(move || open_backend(&path))
+    }
+
+    /// Connects to an EC through the given [`Backend`].
+    ///
+    /// The resulting handle cannot be reopened with [`EmbeddedController::reopen`].
+    pub fn with_backend(backend: Box<dyn Backend>) -> io::Result<Self> {
+        Ok(Self::connect(
+            backend,
+            None,
+            RetryPolicy::default(),
+            Self::DEFAULT_TIMEOUT,
+        )?)
+    }
+
+    /// Connects to an EC through the [`Backend`] returned by `open`.
+    ///
+    /// `open` is called again whenever the handle is reopened.
+    pub fn with_opener(
+        open: impl Fn() -> io::Result<Box<dyn Backend>> + Send + Sync + 'static,
+    ) -> io::Result<Self> {
+        Ok(Self::connect(
+            open()?,
+            Some(Arc::new(open)),
+            RetryPolicy::default(),
+            Self::DEFAULT_TIMEOUT,
+        )?)
+    }
+
+    /// Returns whether [`EmbeddedController::reopen`] can open a new backend.
+    pub fn can_reopen(&self) -> bool {
+        self.opener.is_some()
+    }
+
+    /// Closes the backend and opens a new one, for example after the EC was reset or its driver
+    /// was reloaded.
+    ///
+    /// Everything learned about the EC (supported commands, protocol limits) is discarded and
+    /// queried again. Fails with an [`io::ErrorKind::Unsupported`] error if the handle was created
+    /// with [`EmbeddedController::with_backend`].
+    pub fn reopen(&mut self) -> Result<(), Error> {
+        let Some(opener) = self.opener.clone() else {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "EC backend cannot be reopened",
+            )));
+        };
+        // Statistics are kept across reconnects, and also if reconnecting fails.
+        let mut new = Self::connect(opener()?, Some(opener), self.retry, self.timeout)?;
+        *new.stats.get_mut().unwrap() = mem::take(self.stats.get_mut().unwrap());
+        *self = new;
+        Ok(())
+    }
+
+    fn connect(
+        backend: Box<dyn Backend>,
+        opener: Option<Opener>,
+        retry: RetryPolicy,
+        timeout: Duration,
+    ) -> Result<Self, Error> {
         let mut this = Self {
-            fd: File::options()
-                .read(true)
-                .write(true)
-                .open("/dev/cros_ec")?,
-            version: IoctlVersion::V1,
+            worker: Worker::spawn(backend)?,
+            opener,
+            retry,
+            timeout,
+            timeouts: AtomicU32::new(0),
+            // Conservative limits until we've asked the EC.
+            protocol: ProtocolInfo::V2,
+            features: None,
+            versions: Mutex::new(HashMap::new()),
+            stats: Mutex::new(HashMap::new()),
+        };
+
+        this.check()?;
+
+        log::info!("connected to embedded controller");
+
+        this.protocol = match this.send(GetProtocolInfo) {
+            Ok(resp) => ProtocolInfo::from_response(&resp),
+            Err(Error::Ec(EcError::InvalidCommand)) => {
+                log::debug!("EC does not support protocol info query, assuming protocol v2");
+                ProtocolInfo::V2
+            }
+            Err(e) => return Err(e),
         };
+        log::debug!("{:?}", this.protocol);
 
-        // The framework EC uses ioctl interface version 2, but this mirrors the logic in ectool
-        // just to make sure it doesn't do something nonsensical on non-Framework machines.
-        this.version = match this.cmd_v1(Hello {
-            in_data: 0xa0b0c0d0,
-        }) {
-            Err(nix::errno::Errno::ENOTTY) => IoctlVersion::V2,
-            _ => IoctlVersion::V1,
+        this.features = match this.command(GetFeatures) {
+            Ok(features) => {
+                log::debug!("EC features: {:?}", features.iter().collect::<Vec<_>>());
+                Some(features)
+            }
+            Err(Error::Ec(EcError::InvalidCommand) | Error::UnsupportedVersion { .. }) => {
+                log::debug!("EC does not support feature query");
+                None
+            }
+            Err(e) => return Err(e),
         };
 
-        log::debug!("ioctl version {:?}", this.version);
+        Ok(this)
+    }
 
-        // Test communication by issuing a `Hello` command and reading back the result.
+    /// Checks that the EC is responsive by issuing a `Hello` command and reading back the result.
+    pub fn check(&self) -> Result<(), Error> {
         let magic = 0xaa55dead;
-        let resp = this.command(Hello { in_data: magic })?;
+        let resp = self.send(Hello { in_data: magic })?;
         let expected = magic + 0x01020304;
         if resp.out_data != expected {
-            return Err(io::Error::new(
+            return Err(Error::Io(io::Error::new(
                 io::ErrorKind::InvalidData,
                 format!(
-                    "failed to connect to EC: invalid response to hello command (received {:010x}, expected {:010x})",
+                    "invalid response to hello command (received {:010x}, expected {:010x})",
                     resp.out_data, expected,
                 ),
-            ));
+            )));
         }
+        Ok(())
+    }
 
-        log::info!("connected to embedded controller");
+    /// Returns the policy for retrying commands that fail with a transient error.
+    pub fn retry_policy(&self) -> RetryPolicy {
+        self.retry
+    }
 
-        Ok(this)
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry = policy;
     }
 
-    #[cfg(windows)]
-    pub fn open() -> io::Result<Self> {
-        panic!()
+    /// Returns how long to wait for an EC transaction before giving up.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
     }
 
-    #[cfg(unix)]
-    pub fn command<C: command::Command>(&self, cmd: C) -> io::Result<C::Response> {
-        match self.version {
-            IoctlVersion::V1 => self.cmd_v1(cmd),
-            IoctlVersion::V2 => self.cmd_v2(cmd),
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Returns `false` if the last few transactions all timed out.
+    ///
+    /// The EC (or the driver talking to it) is probably hung in that case, and further commands
+    /// are likely to time out as well.
+    pub fn is_healthy(&self) -> bool {
+        self.timeouts.load(Ordering::Relaxed) < Self::UNHEALTHY_AFTER
+    }
+
+    /// Runs `f` on the worker thread, waiting at most [`EmbeddedController::timeout`] for it.
+    fn run<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&dyn Backend) -> T + Send + 'static,
+    ) -> Result<T, Error> {
+        self.run_with_timeout(self.timeout, f)
+    }
+
+    fn run_with_timeout<T: Send + 'static>(
+        &self,
+        timeout: Duration,
+        f: impl FnOnce(&dyn Backend) -> T + Send + 'static,
+    ) -> Result<T, Error> {
+        match self.worker.run(timeout, f) {
+            Ok(res) => {
+                if self.timeouts.swap(0, Ordering::Relaxed) >= Self::UNHEALTHY_AFTER {
+                    log::info!("EC is responding again");
+                }
+                Ok(res)
+            }
+            Err(RunError::TimedOut) => {
+                let timeouts = self.timeouts.fetch_add(1, Ordering::Relaxed) + 1;
+                if timeouts == Self::UNHEALTHY_AFTER {
+                    log::warn!("{} EC transactions in a row timed out", timeouts);
+                }
+                Err(Error::Timeout { timeout })
+            }
+            Err(RunError::Gone) => Err(Error::Io(io::Error::other("EC worker thread exited"))),
         }
-        .map_err(Into::into)
     }
 
-    #[cfg(windows)]
-    pub fn command<C: command::Command>(&self, cmd: C) -> io::Result<C::Response> {
-        panic!()
+    /// Returns the host command protocol parameters reported by the EC.
+    pub fn protocol_info(&self) -> ProtocolInfo {
+        self.protocol
     }
 
-    #[cfg(unix)]
-    fn cmd_v1<C: command::Command>(&self, cmd: C) -> nix::Result<C::Response> {
-        let mut resp = MaybeUninit::<C::Response>::uninit();
-        let mut cmd = CommandV1 {
-            version: C::VERSION,
-            command: C::CMD as u32,
-            outdata: bytemuck::bytes_of(&cmd).as_ptr() as *mut _,
-            outsize: size_of_val(&cmd).try_into().unwrap(),
-            indata: resp.as_mut_ptr().cast(),
-            insize: size_of_val(&resp).try_into().unwrap(),
-            result: 0xff,
+    /// Returns the features supported by the EC.
+    ///
+    /// Returns `None` if the EC does not support `EC_CMD_GET_FEATURES`.
+    pub fn features(&self) -> Option<GetFeaturesResponse> {
+        self.features
+    }
+
+    /// Issues a command to the EC.
+    ///
+    /// Fails with [`Error::UnsupportedVersion`] if the EC reports that it doesn't support the
+    /// command's version.
+    pub fn command<C: command::Command>(&self, cmd: C) -> Result<C::Response, Error> {
+        self.check_version(C::CMD as u32, C::VERSION)?;
+        self.send(cmd)
+    }
+
+    /// Fails with [`Error::UnsupportedVersion`] if the EC reports that it doesn't support
+    /// `version` of `command`.
+    fn check_version(&self, command: u32, version: u32) -> Result<(), Error> {
+        if let Some(supported) = self.command_versions(command)?
+            && supported & (1 << version) == 0
+        {
+            return Err(Error::UnsupportedVersion {
+                command,
+                versions: 1 << version,
+                supported,
+            });
+        }
+        Ok(())
+    }
+
+    /// Issues a command in the highest version supported by both sides.
+    pub fn command_versioned<C: Versioned>(&self, cmd: &C) -> Result<C::Output, Error> {
+        let version = self.negotiate_version(C::CMD as u32, C::VERSIONS)?;
+        log::trace!("using version {} of command {:?}", version, C::CMD);
+        cmd.send(self, version)
+    }
+
+    /// Picks the highest version of `command` that both the EC and the caller support.
+    ///
+    /// `versions` is a bitmask of the versions the caller can encode (bit N means version N). If
+    /// the EC can't tell which versions it supports, the lowest of `versions` is used.
+    pub fn negotiate_version(&self, command: u32, versions: u32) -> Result<u32, Error> {
+        let supported = self.command_versions(command)?;
+        let common = match supported {
+            Some(supported) => versions & supported,
+            None => versions & versions.wrapping_neg(),
         };
-        unsafe {
-            let ret = nix::libc::ioctl(
-                std::os::fd::AsRawFd::as_raw_fd(&self.fd),
-                nix::request_code_readwrite!(':', 0, size_of::<CommandV1>()),
-                &mut cmd,
-            );
-            nix::errno::Errno::result(ret)?;
-            Ok(resp.assume_init())
+        if common == 0 {
+            return Err(Error::UnsupportedVersion {
+                command,
+                versions,
+                supported: supported.unwrap_or(0),
+            });
         }
+        Ok(31 - common.leading_zeros())
     }
 
-    #[cfg(unix)]
-    fn cmd_v2<C: command::Command>(&self, cmd: C) -> nix::Result<C::Response> {
-        let mut cmd = CommandV2 {
-            header: CommandV2Header {
-                version: C::VERSION,
-                command: C::CMD as u32,
-                outsize: size_of::<C>().try_into().unwrap(),
-                insize: size_of::<C::Response>().try_into().unwrap(),
-                result: 0xff,
-            },
-            data: CommandV2Union { req: cmd },
+    /// Returns the bitmask of versions of `command` that the EC supports (bit N means version N).
+    ///
+    /// Returns `Ok(None)` if the EC doesn't support querying command versions. The result is
+    /// cached.
+    pub fn command_versions(&self, command: u32) -> Result<Option<u32>, Error> {
+        if let Some(&versions) = self.versions.lock().unwrap().get(&command) {
+            return Ok(versions);
+        }
+
+        let versions = match self.query_command_versions(command) {
+            Ok(resp) => Some(resp.version_mask),
+            // The EC responds with `INVALID_PARAM` if it doesn't know the command at all.
+            Err(Error::Ec(EcError::InvalidParam)) => Some(0),
+            Err(Error::Ec(EcError::InvalidCommand)) => None,
+            Err(e) => return Err(e),
         };
+        match versions {
+            Some(mask) => log::debug!("EC command {command:#06x} supports versions {mask:#b}"),
+            None => log::debug!("EC cannot report supported versions of command {command:#06x}"),
+        }
+        self.versions.lock().unwrap().insert(command, versions);
+        Ok(versions)
+    }
 
-        unsafe {
-            let ret = nix::libc::ioctl(
-                std::os::fd::AsRawFd::as_raw_fd(&self.fd),
-                nix::request_code_readwrite!(0xEC, 0, size_of::<CommandV2Header>()),
-                &mut cmd,
-            );
-            nix::errno::Errno::result(ret)?;
-            Ok(cmd.data.resp)
+    fn query_command_versions(
+        &self,
+        command: u32,
+    ) -> Result<command::GetCmdVersionsResponse, Error> {
+        // Like ectool, try version 1 first since it supports all command IDs, and fall back to
+        // version 0 for older ECs.
+        let cmd = u16::try_from(command).map_err(|_| Error::Ec(EcError::InvalidParam))?;
+        match self.send(GetCmdVersionsV1 { cmd }) {
+            Err(Error::Ec(EcError::InvalidVersion)) => {
+                let cmd = u8::try_from(command).map_err(|_| Error::Ec(EcError::InvalidParam))?;
+                self.send(GetCmdVersions { cmd })
+            }
+            res => res,
         }
     }
-}
 
-#[repr(C)]
-struct CommandV1 {
-    version: u32,
-    command: u32,
-    outdata: *mut u8,
-    outsize: u32,
-    indata: *mut u8,
-    insize: u32,
-    result: u32,
-}
+    /// Issues a command without checking its version against the EC's supported versions.
+    fn send<C: command::Command>(&self, cmd: C) -> Result<C::Response, Error> {
+        // The response starts out zeroed, so any bytes the EC doesn't send are well-defined.
+        let mut resp = C::Response::zeroed();
+        let len = self.command_raw(
+            C::CMD as u32,
+            C::VERSION,
+            bytemuck::bytes_of(&cmd),
+            bytemuck::bytes_of_mut(&mut resp),
+        )?;
+        if len < C::MIN_RESPONSE_SIZE {
+            return Err(Error::ShortResponse {
+                expected: C::MIN_RESPONSE_SIZE,
+                received: len,
+            });
+        }
+        Ok(resp)
+    }
+
+    /// Issues a command with a caller-encoded request and a response of caller-chosen size.
+    ///
+    /// This is meant for commands that don't fit the fixed-size [`command::Command`] model, like
+    /// ones with strings or trailing arrays. Returns the number of bytes the EC wrote to the start
+    /// of `response`.
+    ///
+    /// `request` and `response` must not exceed the sizes in [`EmbeddedController::protocol_info`].
+    pub fn command_raw(
+        &self,
+        command: u32,
+        version: u32,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> Result<usize, Error> {
+        if request.len() > self.protocol.max_request {
+            return Err(Error::RequestTooLarge {
+                size: request.len(),
+                max: self.protocol.max_request,
+            });
+        }
+        if response.len() > self.protocol.max_response {
+            return Err(Error::ResponseTooLarge {
+                size: response.len(),
+                max: self.protocol.max_response,
+            });
+        }
+
+        self.retry
+            .run(command, || self.submit(command, version, request, response))
+    }
+
+    /// Makes a single attempt at delivering a command to the EC.
+    fn submit(
+        &self,
+        command: u32,
+        version: u32,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> Result<usize, Error> {
+        // The worker needs owned buffers, since we might stop waiting for it.
+        let request = request.to_vec();
+        let len = response.len();
+        let (reply, data, latency) = self
+            .run(move |backend| {
+                let start = Instant::now();
+                let mut response = vec![0; len];
+                let reply = backend.command(command, version, &request, &mut response);
+                (reply, response, start.elapsed())
+            })
+            .inspect_err(|e| {
+                if let Error::Timeout { timeout } = e {
+                    self.record_stats(command, *timeout, false);
+                }
+            })?;
+        let res = check_reply(command, version, reply);
+        self.record_stats(command, latency, res.is_ok());
+        response.copy_from_slice(&data);
+        res
+    }
+
+    /// Sends the commands in `batch`, each in its time slot.
+    ///
+    /// Commands that fail with a transient error are retried within their slot. The batch stops
+    /// at the first command that fails for good; its error is part of the returned report. The
+    /// whole batch fails with [`Error::Timeout`] if it takes longer than its last deadline plus the
+    /// [`EmbeddedController::timeout`].
+    ///
+    /// Nothing is sent if the EC doesn't support the version of a command added with
+    /// [`Batch::push`], in which case the batch fails with [`Error::UnsupportedVersion`].
+    pub fn run_batch(&self, batch: Batch) -> Result<BatchReport, Error> {
+        for step in batch.steps.iter().filter(|step| step.check_version) {
+            self.check_version(step.command, step.version)?;
+        }
+        let protocol = self.protocol;
+        let retry = self.retry;
+        let timeout = self.timeout + batch.duration();
+        let report = self.run_with_timeout(timeout, move |backend| {
+            let start = Instant::now();
+            let mut steps = Vec::with_capacity(batch.steps.len());
+            for step in batch.steps {
+                if let Some(wait) = step.not_before.checked_sub(start.elapsed()) {
+                    thread::sleep(wait);
+                }
+                let started = start.elapsed();
+                let mut response = vec![0; step.response_len];
+                let mut attempts = Vec::new();
+                let result = if step.request.len() > protocol.max_request {
+                    Err(Error::RequestTooLarge {
+                        size: step.request.len(),
+                        max: protocol.max_request,
+                    })
+                } else if step.response_len > protocol.max_response {
+                    Err(Error::ResponseTooLarge {
+                        size: step.response_len,
+                        max: protocol.max_response,
+                    })
+                } else {
+                    retry
+                        .run(step.command, || {
+                            let sent = Instant::now();
+                            let reply = backend.command(
+                                step.command,
+                                step.version,
+                                &step.request,
+                                &mut response,
+                            );
+                            let res = check_reply(step.command, step.version, reply);
+                            attempts.push((sent.elapsed(), res.is_ok()));
+                            res
+                        })
+                        .and_then(|len| {
+                            if len < step.min_response_len {
+                                return Err(Error::ShortResponse {
+                                    expected: step.min_response_len,
+                                    received: len,
+                                });
+                            }
+                            Ok(len)
+                        })
+                };
+                let failed = result.is_err();
+                steps.push(StepReport {
+                    command: step.command,
+                    deadline: step.deadline,
+                    started,
+                    finished: start.elapsed(),
+                    result: result.map(|len| {
+                        response.truncate(len);
+                        response
+                    }),
+                    attempts,
+                });
+                if failed {
+                    break;
+                }
+            }
+            BatchReport {
+                steps,
+                elapsed: start.elapsed(),
+            }
+        })?;
+        for step in &report.steps {
+            for &(latency, ok) in &step.attempts {
+                self.record_stats(step.command, latency, ok);
+            }
+        }
+        Ok(report)
+    }
+
+    fn record_stats(&self, command: u32, latency: Duration, ok: bool) {
+        self.stats
+            .lock()
+            .unwrap()
+            .entry(command)
+            .or_default()
+            .record(latency, ok);
+    }
+
+    /// Returns latency and error statistics for every command sent so far, ordered by command
+    /// code.
+    ///
+    /// Every attempt at delivering a command counts, so retried commands are counted more than
+    /// once. The statistics survive [`EmbeddedController::reopen`].
+    pub fn stats(&self) -> Vec<(u32, CommandStats)> {
+        let mut stats = self
+            .stats
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&command, stats)| (command, stats.clone()))
+            .collect::<Vec<_>>();
+        stats.sort_by_key(|&(command, _)| command);
+        stats
+    }
+
+    /// Like [`EmbeddedController::command_raw`], but returns the response as a [`Vec`].
+    ///
+    /// At most `max_response` bytes are accepted from the EC, or the EC's maximum response size,
+    /// whichever is smaller.
+    pub fn command_vec(
+        &self,
+        command: u32,
+        version: u32,
+        request: &[u8],
+        max_response: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let mut response = vec![0; max_response.min(self.protocol.max_response)];
+        let len = self.command_raw(command, version, request, &mut response)?;
+        response.truncate(len);
+        Ok(response)
+    }
+
+    /// Reads raw bytes from the EC's memory-mapped region, starting at `offset`.
+    ///
+    /// Returns the number of bytes read.
+    pub fn read_memmap(&self, offset: u8, buf: &mut [u8]) -> Result<usize, Error> {
+        if usize::from(offset) + buf.len() > memmap::SIZE {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "read exceeds the EC's memory-mapped region",
+            )));
+        }
+        let len = buf.len();
+        let (res, data) = self.run(move |backend| {
+            let mut buf = vec![0; len];
+            (backend.read_memmap(offset, &mut buf), buf)
+        })?;
+        let read = res?;
+        buf.copy_from_slice(&data);
+        Ok(read)
+    }
+
+    /// Reads a value from the EC's memory-mapped region.
+    ///
+    /// This is much cheaper than a host command, but only works with backends that can access the
+    /// memory-mapped region.
+    pub fn memmap<R: memmap::Region>(&self) -> Result<R, Error> {
+        let mut value = R::zeroed();
+        let buf = bytemuck::bytes_of_mut(&mut value);
+        let expected = buf.len();
+        let len = self.read_memmap(R::OFFSET, buf)?;
+        if len < expected {
+            return Err(Error::ShortResponse {
+                expected,
+                received: len,
+            });
+        }
+        Ok(value)
+    }
+
+    /// Subscribes to the MKBP event types in `mask` (bit N means event type N).
+    ///
+    /// Pending events can then be retrieved with [`EmbeddedController::read_event`].
+    pub fn subscribe(&self, mask: u32) -> Result<(), Error> {
+        Ok(self.run(move |backend| backend.set_event_mask(mask))??)
+    }
 
-#[repr(C)]
-struct CommandV2<C: command::Command> {
-    header: CommandV2Header,
-    // Request and response are stored in a `union` rather than using an empty trailing array like
-    // the C code does. I believe this is ABI-equivalent, so it shouldn't cause problems.
-    data: CommandV2Union<C>,
+    /// Returns the next pending event, or `None` if there is none.
+    pub fn read_event(&self) -> Result<Option<Event>, Error> {
+        let (res, buf) = self.run(|backend| {
+            let mut buf = [0; 64];
+            (backend.read_event(&mut buf), buf)
+        })?;
+        match res {
+            Ok(len) => Event::parse(&buf[..len])
+                .map(Some)
+                .ok_or(Error::ShortResponse {
+                    expected: 1,
+                    received: len,
+                }),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Returns a file descriptor that becomes readable when an event is pending.
+    ///
+    /// Returns `None` if the backend does not support events.
+    #[cfg(unix)]
+    pub fn event_fd(&self) -> Option<RawFd> {
+        self.run(|backend| backend.event_fd()).ok().flatten()
+    }
 }
 
-#[repr(C)]
-struct CommandV2Header {
-    version: u32,
-    command: u32,
-    outsize: u32,
-    insize: u32,
-    result: u32,
+/// Turns the outcome of a [`Backend::command`] call into the response length or an [`Error`].
+fn check_reply(command: u32, version: u32, reply: io::Result<Reply>) -> Result<usize, Error> {
+    let reply = reply?;
+    if let Some(e) = EcError::from_code(reply.result) {
+        log::debug!(
+            "EC command {:#06x} (version {}) failed: {}",
+            command,
+            version,
+            e
+        );
+        return Err(e.into());
+    }
+    Ok(reply.len)
 }
 
-#[repr(C)]
-union CommandV2Union<C: command::Command> {
-    req: C,
-    resp: C::Response,
+// `EmbeddedController` is meant to be shared between threads.
+const _: () = {
+    const fn assert_sync<T: Send + Sync>() {}
+    assert_sync::<EmbeddedController>();
+};
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+
+    use super::{sim::SimulatedEc, *};
+    use crate::command::{Cmd, Hello};
+
+    #[test]
+    fn stats_survive_reopen() {
+        let fail = Arc::new(AtomicBool::new(false));
+        let mut ec = EmbeddedController::with_opener({
+            let fail = fail.clone();
+            move || match fail.load(Ordering::Relaxed) {
+                true => Err(io::ErrorKind::NotFound.into()),
+                false => Ok(Box::new(SimulatedEc::default())),
+            }
+        })
+        .unwrap();
+        let hellos = |ec: &EmbeddedController| {
+            ec.stats()
+                .into_iter()
+                .find(|&(command, _)| command == Cmd::Hello as u32)
+                .map_or(0, |(_, stats)| stats.latency.count())
+        };
+        ec.command(Hello { in_data: 0 }).unwrap();
+        let sent = hellos(&ec);
+        assert!(sent > 0);
+
+        fail.store(true, Ordering::Relaxed);
+        ec.reopen().unwrap_err();
+        assert_eq!(hellos(&ec), sent);
+
+        fail.store(false, Ordering::Relaxed);
+        ec.reopen().unwrap();
+        assert_eq!(hellos(&ec), sent);
+    }
 }
@@ -0,0 +1,386 @@
+//! An `async` interface to the EC, for use from executors that must not block.
+//!
+//! Commands are still executed by the blocking [`EmbeddedController`], but on a dedicated thread,
+//! and their results are delivered through [`EcFuture`]s. This doesn't depend on any particular
+//! async runtime.
+
+use std::{
+    future::Future,
+    io, mem,
+    pin::Pin,
+    sync::{Arc, Condvar, Mutex, mpsc},
+    task::{Context, Poll, Waker},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::command::Command;
+
+use super::{EmbeddedController, Error};
+
+type Job = Box<dyn FnOnce(&EmbeddedController) + Send>;
+
+/// An async handle to an [`EmbeddedController`].
+///
+/// Requests are executed one at a time, in the order they were made, on a thread owned by the
+/// handle. Dropping an [`EcFuture`] cancels its request if it hasn't been sent to the EC yet.
+pub struct AsyncEmbeddedController {
+    ec: Arc<EmbeddedController>,
+    jobs: mpsc::Sender<Job>,
+    timer: Arc<Timer>,
+}
+
+impl AsyncEmbeddedController {
+    pub fn new(ec: EmbeddedController) -> io::Result<Self> {
+        let ec = Arc::new(ec);
+        let (jobs, rx) = mpsc::channel::<Job>();
+        let dispatcher = ec.clone();
+        thread::Builder::new()
+            .name("ec-async".into())
+            .spawn(move || {
+                // Exits once the handle is dropped and all queued requests are done.
+                for job in rx {
+                    job(&dispatcher);
+                }
+            })?;
+
+        let timer = Arc::new(Timer::default());
+        let t = timer.clone();
+        thread::Builder::new()
+            .name("ec-async-timer".into())
+            .spawn(move || t.run())?;
+
+        Ok(Self { ec, jobs, timer })
+    }
+
+    /// Returns the underlying blocking handle, eg. to query [`EmbeddedController::stats`].
+    ///
+    /// Calling blocking methods on it from an async context defeats the purpose of this type.
+    pub fn blocking(&self) -> &EmbeddedController {
+        &self.ec
+    }
+
+    /// Issues a command to the EC, see [`EmbeddedController::command`].
+    pub fn command<C>(&self, cmd: C) -> EcFuture<C::Response>
+    where
+        C: Command + Send + 'static,
+        C::Response: Send,
+    {
+        self.run(move |ec| ec.command(cmd))
+    }
+
+    /// Issues a raw command to the EC, see [`EmbeddedController::command_vec`].
+    pub fn command_vec(
+        &self,
+        command: u32,
+        version: u32,
+        request: Vec<u8>,
+        max_response: usize,
+    ) -> EcFuture<Vec<u8>> {
+        self.run(move |ec| ec.command_vec(command, version, &request, max_response))
+    }
+
+    /// Runs `f` with the blocking handle on the EC thread.
+    ///
+    /// `f` should only talk to the EC, since it holds up every other request while it runs.
+    pub fn run<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&EmbeddedController) -> Result<T, Error> + Send + 'static,
+    ) -> EcFuture<T> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                result: None,
+                waker: None,
+                cancelled: false,
+            }),
+        });
+        let job = shared.clone();
+        let sent = self.jobs.send(Box::new(move |ec| {
+            if job.state.lock().unwrap().cancelled {
+                return;
+            }
+            let result = f(ec);
+            job.complete(result);
+        }));
+        if sent.is_err() {
+            shared.complete(Err(Error::Io(io::Error::other("EC thread exited"))));
+        }
+        EcFuture {
+            shared,
+            timer: self.timer.clone(),
+            created: Instant::now(),
+        }
+    }
+}
+
+impl Drop for AsyncEmbeddedController {
+    fn drop(&mut self) {
+        self.timer.shut_down();
+    }
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+}
+
+struct State<T> {
+    result: Option<Result<T, Error>>,
+    waker: Option<Waker>,
+    /// Set when the future is dropped or its deadline passed, so that the request is skipped if
+    /// it hasn't started yet.
+    cancelled: bool,
+}
+
+impl<T> Shared<T> {
+    /// Stores the result (unless there already is one) and wakes the task waiting for it.
+    fn complete(&self, result: Result<T, Error>) {
+        let mut state = self.state.lock().unwrap();
+        if state.result.is_none() {
+            state.result = Some(result);
+        }
+        let waker = state.waker.take();
+        drop(state);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// The pending result of a request made through [`AsyncEmbeddedController`].
+///
+/// Dropping the future cancels the request, unless it's already being executed. EC transactions
+/// can't be interrupted, so in that case the request runs to completion and its result is
+/// discarded.
+#[must_use = "futures do nothing unless polled, and dropping this one cancels the request"]
+pub struct EcFuture<T> {
+    shared: Arc<Shared<T>>,
+    timer: Arc<Timer>,
+    created: Instant,
+}
+
+impl<T: Send + 'static> EcFuture<T> {
+    /// Fails the request with [`Error::Timeout`] if it hasn't finished by `deadline`.
+    ///
+    /// A request that hasn't been sent to the EC by then is cancelled.
+    pub fn deadline(self, deadline: Instant) -> Self {
+        let timeout = deadline.saturating_duration_since(self.created);
+        // Doesn't keep the request alive, there's nothing to do once the future is gone.
+        let shared = Arc::downgrade(&self.shared);
+        self.timer.add(
+            deadline,
+            Box::new(move || {
+                if let Some(shared) = shared.upgrade() {
+                    shared.state.lock().unwrap().cancelled = true;
+                    shared.complete(Err(Error::Timeout { timeout }));
+                }
+            }),
+        );
+        self
+    }
+
+    /// Fails the request with [`Error::Timeout`] if it hasn't finished within `timeout` from
+    /// when it was made.
+    pub fn timeout(self, timeout: Duration) -> Self {
+        let deadline = self.created + timeout;
+        self.deadline(deadline)
+    }
+}
+
+impl<T> Future for EcFuture<T> {
+    type Output = Result<T, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.shared.state.lock().unwrap();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> Drop for EcFuture<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().cancelled = true;
+    }
+}
+
+type Expiry = Box<dyn FnOnce() + Send>;
+
+/// Fires the deadlines of pending [`EcFuture`]s.
+#[derive(Default)]
+struct Timer {
+    state: Mutex<TimerState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct TimerState {
+    deadlines: Vec<(Instant, Expiry)>,
+    shut_down: bool,
+}
+
+impl Timer {
+    fn add(&self, deadline: Instant, expire: Expiry) {
+        self.state
+            .lock()
+            .unwrap()
+            .deadlines
+            .push((deadline, expire));
+        self.changed.notify_one();
+    }
+
+    fn shut_down(&self) {
+        self.state.lock().unwrap().shut_down = true;
+        self.changed.notify_one();
+    }
+
+    fn run(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let (expired, pending) = mem::take(&mut state.deadlines)
+                .into_iter()
+                .partition::<Vec<_>, _>(|(deadline, _)| *deadline <= now);
+            state.deadlines = pending;
+
+            if !expired.is_empty() {
+                drop(state);
+                for (_, expire) in expired {
+                    expire();
+                }
+                state = self.state.lock().unwrap();
+                continue;
+            }
+
+            // Outstanding deadlines keep the timer alive after the handle is gone.
+            if state.shut_down && state.deadlines.is_empty() {
+                return;
+            }
+            state = match state.deadlines.iter().map(|(deadline, _)| *deadline).min() {
+                Some(next) => {
+                    self.changed
+                        .wait_timeout(state, next.saturating_duration_since(now))
+                        .unwrap()
+                        .0
+                }
+                None => self.changed.wait(state).unwrap(),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{pin::pin, task::Wake};
+
+    use super::*;
+    use crate::{
+        command::{GetKeyboardBacklight, SetKeyboardBacklight},
+        ec::sim::FaultyEc,
+    };
+
+    fn connect(latency: Duration) -> (Arc<FaultyEc>, AsyncEmbeddedController) {
+        let (ec, controller) = FaultyEc::connect();
+        ec.set_latency(latency);
+        (ec, AsyncEmbeddedController::new(controller).unwrap())
+    }
+
+    /// Minimal executor that parks the thread until the future is woken.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        struct Unpark(thread::Thread);
+
+        impl Wake for Unpark {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+
+        let mut future = pin!(future);
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    fn set(percent: u8) -> SetKeyboardBacklight {
+        SetKeyboardBacklight { percent }
+    }
+
+    #[test]
+    fn commands() {
+        let (ec, controller) = connect(Duration::ZERO);
+        block_on(controller.command(set(30))).unwrap();
+        assert_eq!(
+            block_on(controller.command(GetKeyboardBacklight))
+                .unwrap()
+                .percent,
+            30
+        );
+        assert_eq!(ec.sim.state().percent, 30);
+    }
+
+    #[test]
+    fn requests_run_in_order() {
+        let (ec, controller) = connect(Duration::from_millis(5));
+        let first = controller.command(set(10));
+        let second = controller.command(set(20));
+        block_on(second).unwrap();
+        block_on(first).unwrap();
+        assert_eq!(ec.sim.state().percent, 20);
+    }
+
+    #[test]
+    fn timeout() {
+        let (_, controller) = connect(Duration::from_millis(200));
+        let start = Instant::now();
+        let res = block_on(
+            controller
+                .command(set(10))
+                .timeout(Duration::from_millis(10)),
+        );
+        assert!(
+            matches!(res, Err(Error::Timeout { timeout }) if timeout == Duration::from_millis(10))
+        );
+        assert!(start.elapsed() < Duration::from_millis(200));
+    }
+
+    #[test]
+    fn dropped_requests_are_cancelled() {
+        let (ec, controller) = connect(Duration::from_millis(50));
+        let first = controller.command(set(10));
+        drop(controller.command(set(20)));
+        block_on(first).unwrap();
+        assert_eq!(
+            block_on(controller.command(GetKeyboardBacklight))
+                .unwrap()
+                .percent,
+            10
+        );
+        assert_eq!(ec.sim.state().percent, 10);
+    }
+
+    #[test]
+    fn expired_requests_are_cancelled() {
+        let (ec, controller) = connect(Duration::from_millis(50));
+        let first = controller.command(set(10));
+        let second = controller
+            .command(set(20))
+            .timeout(Duration::from_millis(10));
+        assert!(matches!(block_on(second), Err(Error::Timeout { .. })));
+        block_on(first).unwrap();
+        assert_eq!(
+            block_on(controller.command(GetKeyboardBacklight))
+                .unwrap()
+                .percent,
+            10
+        );
+        assert_eq!(ec.sim.state().percent, 10);
+    }
+}
@@ -0,0 +1,394 @@
+//! Sequences of commands that are sent on a schedule.
+
+use std::time::Duration;
+
+use crate::command::Command;
+
+use super::Error;
+
+/// A sequence of commands that [`EmbeddedController::run_batch`] sends back to back, each in its
+/// own time slot.
+///
+/// The whole batch is handed to the EC's worker thread at once, so there's no per-command
+/// overhead from waking up the caller. Slots are measured from the start of the batch, so a slow
+/// transaction doesn't delay the steps after it unless it overruns their slots too.
+///
+/// [`EmbeddedController::run_batch`]: super::EmbeddedController::run_batch
+#[derive(Debug, Clone, Default)]
+pub struct Batch {
+    pub(super) steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct Step {
+    pub(super) command: u32,
+    pub(super) version: u32,
+    pub(super) request: Vec<u8>,
+    pub(super) response_len: usize,
+    /// Replies shorter than this fail with [`Error::ShortResponse`].
+    pub(super) min_response_len: usize,
+    /// Whether `version` is checked against the versions the EC supports.
+    pub(super) check_version: bool,
+    pub(super) not_before: Duration,
+    pub(super) deadline: Duration,
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a command that is sent no earlier than `not_before`, and should be done by `deadline`
+    /// (both relative to the start of the batch).
+    ///
+    /// Like [`EmbeddedController::command`], the command's version is checked against the ones the
+    /// EC supports, and a reply shorter than the response fails the step.
+    ///
+    /// [`EmbeddedController::command`]: super::EmbeddedController::command
+    pub fn push<C: Command>(&mut self, cmd: C, not_before: Duration, deadline: Duration) {
+        self.steps.push(Step {
+            command: C::CMD as u32,
+            version: C::VERSION,
+            request: bytemuck::bytes_of(&cmd).to_vec(),
+            response_len: size_of::<C::Response>(),
+            min_response_len: C::MIN_RESPONSE_SIZE,
+            check_version: true,
+            not_before,
+            deadline,
+        });
+    }
+
+    /// Adds a command with a caller-encoded request, see [`Batch::push`].
+    ///
+    /// Like [`EmbeddedController::command_raw`], this doesn't check the command's version, and
+    /// accepts replies of any length up to `response_len`.
+    ///
+    /// [`EmbeddedController::command_raw`]: super::EmbeddedController::command_raw
+    pub fn push_raw(
+        &mut self,
+        command: u32,
+        version: u32,
+        request: &[u8],
+        response_len: usize,
+        not_before: Duration,
+        deadline: Duration,
+    ) {
+        self.steps.push(Step {
+            command,
+            version,
+            request: request.to_vec(),
+            response_len,
+            min_response_len: 0,
+            check_version: false,
+            not_before,
+            deadline,
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Returns the latest deadline of any step.
+    pub fn duration(&self) -> Duration {
+        self.steps
+            .iter()
+            .map(|step| step.deadline)
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+/// How one step of a [`Batch`] went.
+#[derive(Debug)]
+pub struct StepReport {
+    pub command: u32,
+    /// When the step should have been done, relative to the start of the batch.
+    pub deadline: Duration,
+    /// When the command was sent.
+    pub started: Duration,
+    /// When the EC's reply arrived.
+    pub finished: Duration,
+    /// The response bytes sent by the EC, or why the command failed.
+    pub result: Result<Vec<u8>, Error>,
+    /// How long each attempt at delivering the command took, and whether it succeeded.
+    pub(super) attempts: Vec<(Duration, bool)>,
+}
+
+impl StepReport {
+    pub fn missed_deadline(&self) -> bool {
+        self.finished > self.deadline
+    }
+
+    /// Returns by how much the step missed its deadline (zero if it didn't).
+    pub fn lateness(&self) -> Duration {
+        self.finished.saturating_sub(self.deadline)
+    }
+}
+
+/// The outcome of [`EmbeddedController::run_batch`].
+///
+/// The batch stops at the first step that fails, so `steps` may be shorter than the batch.
+///
+/// [`EmbeddedController::run_batch`]: super::EmbeddedController::run_batch
+#[derive(Debug)]
+pub struct BatchReport {
+    pub steps: Vec<StepReport>,
+    /// How long the whole batch took.
+    pub elapsed: Duration,
+}
+
+impl BatchReport {
+    /// Returns the number of steps that missed their deadline.
+    pub fn missed(&self) -> usize {
+        self.steps.iter().filter(|s| s.missed_deadline()).count()
+    }
+
+    /// Returns by how much the latest step missed its deadline.
+    pub fn max_lateness(&self) -> Duration {
+        self.steps
+            .iter()
+            .map(StepReport::lateness)
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Returns the error that stopped the batch, if any.
+    pub fn error(&self) -> Option<&Error> {
+        self.steps.last()?.result.as_ref().err()
+    }
+
+    /// Turns a failed batch into its error.
+    pub fn into_result(mut self) -> Result<Self, Error> {
+        match self.steps.pop_if(|step| step.result.is_err()) {
+            Some(StepReport { result: Err(e), .. }) => Err(e),
+            _ => Ok(self),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        command::{Cmd, GetKeyboardBacklight, SetKeyboardBacklight},
+        ec::{EcError, EmbeddedController, RetryPolicy, sim::FaultyEc},
+    };
+
+    fn connect() -> (Arc<FaultyEc>, EmbeddedController) {
+        let (ec, mut controller) = FaultyEc::connect();
+        controller.set_retry_policy(RetryPolicy::NONE);
+        (ec, controller)
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn set(percent: u8) -> SetKeyboardBacklight {
+        SetKeyboardBacklight { percent }
+    }
+
+    #[test]
+    fn steps_run_in_their_slots() {
+        let (ec, controller) = connect();
+        let mut batch = Batch::new();
+        for (i, percent) in [10, 20, 30].into_iter().enumerate() {
+            let slot = ms(5 * i as u64);
+            batch.push(set(percent), slot, slot + ms(500));
+        }
+        assert_eq!(batch.duration(), ms(510));
+
+        let report = controller.run_batch(batch).unwrap();
+        assert_eq!(report.steps.len(), 3);
+        for (i, step) in report.steps.iter().enumerate() {
+            assert_eq!(step.command, Cmd::SetKeyboardBacklight as u32);
+            assert!(step.started >= ms(5 * i as u64));
+            assert!(step.finished >= step.started);
+            assert_eq!(step.result.as_deref().unwrap(), &[] as &[u8]);
+        }
+        assert_eq!(report.missed(), 0);
+        assert!(report.error().is_none());
+        assert!(report.into_result().is_ok());
+        assert_eq!(ec.sim.state().percent, 30);
+    }
+
+    #[test]
+    fn failure_stops_the_batch() {
+        let (ec, controller) = connect();
+        let mut batch = Batch::new();
+        for percent in [10, 101, 30] {
+            batch.push(set(percent), Duration::ZERO, ms(500));
+        }
+        let report = controller.run_batch(batch).unwrap();
+        assert_eq!(report.steps.len(), 2);
+        assert!(matches!(
+            report.error(),
+            Some(Error::Ec(EcError::InvalidParam))
+        ));
+        assert!(matches!(
+            report.into_result(),
+            Err(Error::Ec(EcError::InvalidParam))
+        ));
+        assert_eq!(ec.sim.state().percent, 10);
+    }
+
+    #[test]
+    fn transient_errors_are_retried() {
+        let (ec, mut controller) = connect();
+        controller.set_retry_policy(RetryPolicy {
+            attempts: 3,
+            ..RetryPolicy::NONE
+        });
+        ec.fail_busy(2);
+        let mut batch = Batch::new();
+        batch.push(set(40), Duration::ZERO, ms(500));
+        controller.run_batch(batch).unwrap().into_result().unwrap();
+        assert_eq!(ec.sim.state().percent, 40);
+
+        ec.fail_busy(3);
+        let mut batch = Batch::new();
+        batch.push(set(50), Duration::ZERO, ms(500));
+        let report = controller.run_batch(batch).unwrap();
+        assert!(matches!(report.error(), Some(Error::Ec(EcError::Busy))));
+        assert_eq!(ec.sim.state().percent, 40);
+    }
+
+    #[test]
+    fn every_attempt_counts() {
+        let (ec, mut controller) = connect();
+        controller.set_retry_policy(RetryPolicy {
+            attempts: 2,
+            initial_backoff: ms(50),
+            max_backoff: ms(50),
+        });
+        controller
+            .command_versions(Cmd::SetKeyboardBacklight as u32)
+            .unwrap();
+        ec.fail_busy(1);
+        let mut batch = Batch::new();
+        batch.push(set(40), Duration::ZERO, ms(500));
+        controller.run_batch(batch).unwrap().into_result().unwrap();
+
+        let (_, stats) = controller
+            .stats()
+            .into_iter()
+            .find(|&(command, _)| command == Cmd::SetKeyboardBacklight as u32)
+            .unwrap();
+        assert_eq!(stats.latency.count(), 2);
+        assert_eq!(stats.errors, 1);
+        // The delay between the attempts isn't part of either.
+        assert!(stats.latency.max() < ms(50));
+    }
+
+    #[test]
+    fn missed_deadlines() {
+        let (ec, controller) = connect();
+        ec.set_latency(ms(20));
+        let mut batch = Batch::new();
+        batch.push(set(10), Duration::ZERO, ms(5));
+        batch.push(set(20), ms(5), ms(500));
+        let report = controller.run_batch(batch).unwrap();
+        assert_eq!(report.missed(), 1);
+        assert!(report.steps[0].missed_deadline());
+        assert!(!report.steps[1].missed_deadline());
+        assert!(report.max_lateness() >= ms(15));
+        assert_eq!(report.max_lateness(), report.steps[0].lateness());
+        // The second step was due while the first one was still running.
+        assert!(report.steps[1].started >= report.steps[0].finished);
+    }
+
+    #[test]
+    fn batch_timeout() {
+        let (ec, mut controller) = connect();
+        // Only the batch itself should be slow, not the version check that precedes it.
+        controller
+            .command_versions(Cmd::SetKeyboardBacklight as u32)
+            .unwrap();
+        controller.set_timeout(ms(10));
+        ec.set_latency(ms(100));
+        let mut batch = Batch::new();
+        batch.push(set(10), Duration::ZERO, ms(10));
+        assert!(matches!(
+            controller.run_batch(batch),
+            Err(Error::Timeout { timeout }) if timeout == ms(20)
+        ));
+    }
+
+    #[test]
+    fn unsupported_versions_are_not_sent() {
+        let (ec, controller) = connect();
+        let code = Cmd::SetKeyboardBacklight as u32;
+        ec.sim.disable_command(Cmd::SetKeyboardBacklight);
+        let mut batch = Batch::new();
+        batch.push(set(10), Duration::ZERO, ms(500));
+        assert!(matches!(
+            controller.run_batch(batch),
+            Err(Error::UnsupportedVersion { command, .. }) if command == code
+        ));
+        assert!(
+            controller
+                .stats()
+                .iter()
+                .all(|&(command, _)| command != code)
+        );
+
+        // Raw commands are sent regardless.
+        let mut batch = Batch::new();
+        batch.push_raw(code, 0, &[10], 0, Duration::ZERO, ms(500));
+        let report = controller.run_batch(batch).unwrap();
+        assert!(matches!(
+            report.error(),
+            Some(Error::Ec(EcError::InvalidCommand))
+        ));
+    }
+
+    #[test]
+    fn short_responses() {
+        let (ec, controller) = connect();
+        for cmd in [Cmd::SetKeyboardBacklight, Cmd::GetKeyboardBacklight] {
+            controller.command_versions(cmd as u32).unwrap();
+        }
+        ec.truncate_responses(1);
+        let mut batch = Batch::new();
+        batch.push(set(10), Duration::ZERO, ms(500));
+        batch.push(GetKeyboardBacklight, Duration::ZERO, ms(500));
+        batch.push(set(20), Duration::ZERO, ms(500));
+        let report = controller.run_batch(batch).unwrap();
+        assert_eq!(report.steps.len(), 2);
+        assert!(matches!(
+            report.error(),
+            Some(Error::ShortResponse {
+                expected: 2,
+                received: 1
+            })
+        ));
+        assert_eq!(ec.sim.state().percent, 10);
+    }
+
+    #[test]
+    fn oversized_requests() {
+        let (_, controller) = connect();
+        let max = controller.protocol_info().max_request;
+        let mut batch = Batch::new();
+        batch.push_raw(
+            Cmd::SetKeyboardBacklight as u32,
+            0,
+            &vec![0; max + 1],
+            0,
+            Duration::ZERO,
+            ms(500),
+        );
+        let report = controller.run_batch(batch).unwrap();
+        assert!(matches!(
+            report.error(),
+            Some(&Error::RequestTooLarge { size, .. }) if size == max + 1
+        ));
+    }
+}
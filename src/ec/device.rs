@@ -0,0 +1,69 @@
+//! Discovery of the EC devices registered with the kernel's `cros_ec` drivers.
+//!
+//! Besides the main EC (`cros_ec`), a machine may have separate ECs for the fingerprint sensor
+//! (`cros_fp`), the sensor hub (`cros_ish`) or USB-PD (`cros_pd`). Each of them gets its own
+//! character device that speaks the same protocol.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Where the `chromeos` device class lists its devices.
+const CLASS_DIR: &str = "/sys/class/chromeos";
+
+/// An EC device found in sysfs.
+#[derive(Debug, Clone)]
+pub struct Device {
+    /// The name of the device, eg. `cros_ec` or `cros_fp`.
+    pub name: String,
+    /// The character device node used to talk to the EC.
+    pub path: PathBuf,
+    /// The contents of the device's `version` attribute, if it could be read.
+    ///
+    /// This is a list of `Key: value` lines, see [`Device::version_field`].
+    pub version: Option<String>,
+}
+
+impl Device {
+    /// Looks up a field (eg. `RW version` or `Firmware copy`) in the device's `version` attribute.
+    pub fn version_field(&self, key: &str) -> Option<&str> {
+        self.version.as_deref()?.lines().find_map(|line| {
+            let (k, v) = line.split_once(':')?;
+            (k.trim() == key).then(|| v.trim())
+        })
+    }
+}
+
+/// Lists the EC devices known to the kernel, sorted by name.
+///
+/// Returns an empty list if the `cros_ec` driver isn't loaded.
+pub fn enumerate() -> io::Result<Vec<Device>> {
+    let entries = match fs::read_dir(CLASS_DIR) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut devices = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        let version = match fs::read_to_string(entry.path().join("version")) {
+            Ok(version) => Some(version.trim_end().to_string()),
+            Err(e) => {
+                log::debug!("cannot read version of EC device {}: {}", name, e);
+                None
+            }
+        };
+        devices.push(Device {
+            path: Path::new("/dev").join(&name),
+            name,
+            version,
+        });
+    }
+    devices.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(devices)
+}
@@ -0,0 +1,263 @@
+use std::{error, fmt, io, time::Duration};
+
+/// An error result reported by the Embedded Controller itself.
+///
+/// These are the `EC_RES_*` codes from `ec_commands.h` (except `EC_RES_SUCCESS`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EcError {
+    InvalidCommand,
+    Error,
+    InvalidParam,
+    AccessDenied,
+    InvalidResponse,
+    InvalidVersion,
+    InvalidChecksum,
+    /// Accepted, command in progress.
+    InProgress,
+    /// No response available.
+    Unavailable,
+    /// We got a timeout.
+    Timeout,
+    /// Table / data overflow.
+    Overflow,
+    /// Header contains invalid data.
+    InvalidHeader,
+    /// Didn't get the entire request.
+    RequestTruncated,
+    /// Response was too big to handle.
+    ResponseTooBig,
+    /// Communications bus error.
+    BusError,
+    /// Up but too busy. Should retry.
+    Busy,
+    /// Header version invalid.
+    InvalidHeaderVersion,
+    /// Header CRC invalid.
+    InvalidHeaderCrc,
+    /// Data CRC invalid.
+    InvalidDataCrc,
+    /// Can't resend response.
+    DupUnavailable,
+    /// A result code this version of keylightd does not know about.
+    Unknown(u32),
+}
+
+impl EcError {
+    /// The `EC_RES_SUCCESS` result code.
+    pub const SUCCESS: u32 = 0;
+
+    /// Decodes an EC result code, returning `None` if it indicates success.
+    pub fn from_code(code: u32) -> Option<Self> {
+        Some(match code {
+            Self::SUCCESS => return None,
+            1 => Self::InvalidCommand,
+            2 => Self::Error,
+            3 => Self::InvalidParam,
+            4 => Self::AccessDenied,
+            5 => Self::InvalidResponse,
+            6 => Self::InvalidVersion,
+            7 => Self::InvalidChecksum,
+            8 => Self::InProgress,
+            9 => Self::Unavailable,
+            10 => Self::Timeout,
+            11 => Self::Overflow,
+            12 => Self::InvalidHeader,
+            13 => Self::RequestTruncated,
+            14 => Self::ResponseTooBig,
+            15 => Self::BusError,
+            16 => Self::Busy,
+            17 => Self::InvalidHeaderVersion,
+            18 => Self::InvalidHeaderCrc,
+            19 => Self::InvalidDataCrc,
+            20 => Self::DupUnavailable,
+            _ => Self::Unknown(code),
+        })
+    }
+
+    /// Returns the numeric `EC_RES_*` code.
+    pub fn code(self) -> u32 {
+        match self {
+            Self::InvalidCommand => 1,
+            Self::Error => 2,
+            Self::InvalidParam => 3,
+            Self::AccessDenied => 4,
+            Self::InvalidResponse => 5,
+            Self::InvalidVersion => 6,
+            Self::InvalidChecksum => 7,
+            Self::InProgress => 8,
+            Self::Unavailable => 9,
+            Self::Timeout => 10,
+            Self::Overflow => 11,
+            Self::InvalidHeader => 12,
+            Self::RequestTruncated => 13,
+            Self::ResponseTooBig => 14,
+            Self::BusError => 15,
+            Self::Busy => 16,
+            Self::InvalidHeaderVersion => 17,
+            Self::InvalidHeaderCrc => 18,
+            Self::InvalidDataCrc => 19,
+            Self::DupUnavailable => 20,
+            Self::Unknown(code) => code,
+        }
+    }
+
+    /// Returns whether the EC is likely to accept the command when it's retried.
+    pub fn is_transient(self) -> bool {
+        self == Self::Busy
+    }
+
+    /// Returns the name of the result code, as used in `ec_commands.h`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::InvalidCommand => "EC_RES_INVALID_COMMAND",
+            Self::Error => "EC_RES_ERROR",
+            Self::InvalidParam => "EC_RES_INVALID_PARAM",
+            Self::AccessDenied => "EC_RES_ACCESS_DENIED",
+            Self::InvalidResponse => "EC_RES_INVALID_RESPONSE",
+            Self::InvalidVersion => "EC_RES_INVALID_VERSION",
+            Self::InvalidChecksum => "EC_RES_INVALID_CHECKSUM",
+            Self::InProgress => "EC_RES_IN_PROGRESS",
+            Self::Unavailable => "EC_RES_UNAVAILABLE",
+            Self::Timeout => "EC_RES_TIMEOUT",
+            Self::Overflow => "EC_RES_OVERFLOW",
+            Self::InvalidHeader => "EC_RES_INVALID_HEADER",
+            Self::RequestTruncated => "EC_RES_REQUEST_TRUNCATED",
+            Self::ResponseTooBig => "EC_RES_RESPONSE_TOO_BIG",
+            Self::BusError => "EC_RES_BUS_ERROR",
+            Self::Busy => "EC_RES_BUSY",
+            Self::InvalidHeaderVersion => "EC_RES_INVALID_HEADER_VERSION",
+            Self::InvalidHeaderCrc => "EC_RES_INVALID_HEADER_CRC",
+            Self::InvalidDataCrc => "EC_RES_INVALID_DATA_CRC",
+            Self::DupUnavailable => "EC_RES_DUP_UNAVAILABLE",
+            Self::Unknown(_) => "unknown result code",
+        }
+    }
+}
+
+impl fmt::Display for EcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.name(), self.code())
+    }
+}
+
+impl error::Error for EcError {}
+
+/// Errors that can occur when issuing a command to the EC.
+#[derive(Debug)]
+pub enum Error {
+    /// The command could not be delivered to the EC (for example, because the ioctl failed).
+    Io(io::Error),
+    /// The EC received the command, but reported an error.
+    Ec(EcError),
+    /// The EC sent fewer response bytes than the command requires.
+    ShortResponse { expected: usize, received: usize },
+    /// The request is larger than what the EC accepts.
+    RequestTooLarge { size: usize, max: usize },
+    /// The expected response is larger than what the EC can send.
+    ResponseTooLarge { size: usize, max: usize },
+    /// The EC doesn't support any of the versions of the command that we can encode.
+    UnsupportedVersion {
+        command: u32,
+        /// Bitmask of the versions we can encode.
+        versions: u32,
+        /// Bitmask of the versions the EC supports (0 if it doesn't know the command).
+        supported: u32,
+    },
+    /// The EC did not respond in time.
+    Timeout { timeout: Duration },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "failed to communicate with EC: {e}"),
+            Self::Ec(e) => write!(f, "EC returned error {e}"),
+            Self::ShortResponse { expected, received } => write!(
+                f,
+                "EC response too short (received {received} bytes, expected at least {expected})"
+            ),
+            Self::RequestTooLarge { size, max } => write!(
+                f,
+                "request of {size} bytes exceeds the EC's maximum request size of {max} bytes"
+            ),
+            Self::ResponseTooLarge { size, max } => write!(
+                f,
+                "response of {size} bytes exceeds the EC's maximum response size of {max} bytes"
+            ),
+            Self::UnsupportedVersion {
+                command,
+                supported: 0,
+                ..
+            } => write!(f, "EC does not support command {command:#06x}"),
+            Self::UnsupportedVersion {
+                command,
+                versions,
+                supported,
+            } => write!(
+                f,
+                "no common version for EC command {command:#06x} (keylightd supports {versions:#b}, EC supports {supported:#b})"
+            ),
+            Self::Timeout { timeout } => write!(f, "EC did not respond within {timeout:?}"),
+        }
+    }
+}
+
+impl Error {
+    /// Returns whether the error is likely to go away when the command is retried.
+    ///
+    /// This is the case when the EC is busy with another command (eg. one issued by `ectool`), or
+    /// when the kernel asks us to try again.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Ec(e) => e.is_transient(),
+            Self::Io(e) => matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock
+                    | io::ErrorKind::Interrupted
+                    | io::ErrorKind::ResourceBusy
+            ),
+            _ => false,
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Ec(e) => Some(e),
+            Self::ShortResponse { .. }
+            | Self::RequestTooLarge { .. }
+            | Self::ResponseTooLarge { .. }
+            | Self::UnsupportedVersion { .. }
+            | Self::Timeout { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<EcError> for Error {
+    fn from(e: EcError) -> Self {
+        Self::Ec(e)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            Error::Ec(_) => io::Error::other(e),
+            Error::ShortResponse { .. } => io::Error::new(io::ErrorKind::InvalidData, e),
+            Error::RequestTooLarge { .. } | Error::ResponseTooLarge { .. } => {
+                io::Error::new(io::ErrorKind::InvalidInput, e)
+            }
+            Error::UnsupportedVersion { .. } => io::Error::new(io::ErrorKind::Unsupported, e),
+            Error::Timeout { .. } => io::Error::new(io::ErrorKind::TimedOut, e),
+        }
+    }
+}
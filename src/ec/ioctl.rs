@@ -0,0 +1,124 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    os::{
+        fd::{AsFd, AsRawFd, RawFd},
+        unix::fs::OpenOptionsExt,
+    },
+    path::Path,
+};
+
+use bytemuck::Zeroable;
+
+use crate::command::{Cmd, GetProtocolInfoResponse, Hello};
+
+use super::{Backend, ProtocolInfo, Reply};
+
+mod marshal;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IoctlVersion {
+    V1,
+    V2,
+}
+
+/// [`Backend`] that uses the ioctl interface of the `cros_ec` character device.
+pub struct Ioctl {
+    fd: File,
+    version: IoctlVersion,
+    /// Size limits reported by the EC, which are enforced before handing commands to the kernel.
+    limits: ProtocolInfo,
+}
+
+impl Ioctl {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut this = Self {
+            // Non-blocking, so that reading events doesn't block when there are none.
+            fd: File::options()
+                .read(true)
+                .write(true)
+                .custom_flags(nix::libc::O_NONBLOCK)
+                .open(path)?,
+            version: IoctlVersion::V1,
+            limits: ProtocolInfo::V2,
+        };
+
+        // The framework EC uses ioctl interface version 2, but this mirrors the logic in ectool
+        // just to make sure it doesn't do something nonsensical on non-Framework machines.
+        let hello = Hello {
+            in_data: 0xa0b0c0d0,
+        };
+        this.version = match marshal::command_v1(
+            this.fd.as_fd(),
+            &this.limits,
+            Cmd::Hello as u32,
+            0,
+            bytemuck::bytes_of(&hello),
+            &mut [0; 4],
+        ) {
+            Err(nix::errno::Errno::ENOTTY) => IoctlVersion::V2,
+            _ => IoctlVersion::V1,
+        };
+
+        log::debug!("ioctl version {:?}", this.version);
+
+        let mut resp = GetProtocolInfoResponse::zeroed();
+        match this.command(
+            Cmd::GetProtocolInfo as u32,
+            0,
+            &[],
+            bytemuck::bytes_of_mut(&mut resp),
+        ) {
+            Ok(reply) if reply.result == 0 => this.limits = ProtocolInfo::from_response(&resp),
+            res => log::debug!("no protocol info ({:?}), assuming protocol v2 limits", res),
+        }
+
+        Ok(this)
+    }
+}
+
+impl Backend for Ioctl {
+    fn command(
+        &self,
+        command: u32,
+        version: u32,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> io::Result<Reply> {
+        let fd = self.fd.as_fd();
+        match self.version {
+            IoctlVersion::V1 => {
+                marshal::command_v1(fd, &self.limits, command, version, request, response)
+            }
+            IoctlVersion::V2 => {
+                marshal::command_v2(fd, &self.limits, command, version, request, response)
+            }
+        }
+        .map_err(Into::into)
+    }
+
+    fn read_memmap(&self, offset: u8, buf: &mut [u8]) -> io::Result<usize> {
+        match self.version {
+            IoctlVersion::V1 => marshal::readmem_v1(self.fd.as_fd(), offset, buf),
+            IoctlVersion::V2 => marshal::readmem_v2(self.fd.as_fd(), offset, buf),
+        }
+        .map_err(Into::into)
+    }
+
+    fn set_event_mask(&self, mask: u32) -> io::Result<()> {
+        // Only the v2 interface supports events.
+        if self.version != IoctlVersion::V2 {
+            return Err(io::ErrorKind::Unsupported.into());
+        }
+        Ok(marshal::set_event_mask(self.fd.as_fd(), mask)?)
+    }
+
+    fn read_event(&self, buf: &mut [u8]) -> io::Result<usize> {
+        // Once an event mask is set, reading from the device returns queued events.
+        (&self.fd).read(buf)
+    }
+
+    fn event_fd(&self) -> Option<RawFd> {
+        Some(self.fd.as_raw_fd())
+    }
+}
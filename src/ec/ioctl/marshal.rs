@@ -0,0 +1,286 @@
+//! Marshalling of requests into the structures expected by the `cros_ec` ioctls.
+//!
+//! This is the only place that talks to the kernel through raw pointers. Everything that crosses
+//! the boundary is built from borrowed slices or from buffers owned by this module, sized and
+//! aligned for the structure the kernel expects, and checked against the EC's limits before the
+//! ioctl is issued.
+//!
+//! There are two generations of the interface:
+//!
+//! - v1 (out-of-tree ChromeOS kernels): `cros_ec_command` and `cros_ec_readmem` point to
+//!   separate request and response buffers.
+//! - v2 (mainline, `include/uapi/linux/cros_ec_dev.h`): `cros_ec_command` is followed by a
+//!   flexible array that holds the request on the way in and the response on the way out, and
+//!   `cros_ec_readmem` embeds a buffer covering the whole memory map.
+
+use std::{
+    mem::{offset_of, size_of},
+    os::fd::{AsRawFd, BorrowedFd},
+};
+
+use bytemuck::{Pod, Zeroable};
+use nix::{errno::Errno, libc};
+
+use crate::{ec::ProtocolInfo, memmap};
+
+use super::super::Reply;
+
+/// Issues ioctl `request` on `fd`.
+///
+/// # Safety
+///
+/// `arg` has to be what the kernel expects for `request`. In particular, if it's a pointer to a
+/// structure, the structure (and any buffers it points to) must be valid for reads and writes of
+/// the sizes encoded in `request` and in the structure itself.
+unsafe fn ioctl<T>(fd: BorrowedFd<'_>, request: libc::c_ulong, arg: T) -> nix::Result<usize> {
+    let ret = unsafe { libc::ioctl(fd.as_raw_fd(), request, arg) };
+    // On success, the command ioctls return the number of response bytes sent by the EC.
+    Errno::result(ret).map(|len| len as usize)
+}
+
+/// Converts a buffer length to the `u32` used by the ioctl structures.
+fn ioctl_size(len: usize) -> nix::Result<u32> {
+    len.try_into().map_err(|_| Errno::EMSGSIZE)
+}
+
+/// Checks a command's buffers against the EC's limits before handing them to the kernel.
+fn check_sizes(limits: &ProtocolInfo, request: &[u8], response: &[u8]) -> nix::Result<()> {
+    if request.len() > limits.max_request || response.len() > limits.max_response {
+        return Err(Errno::EMSGSIZE);
+    }
+    Ok(())
+}
+
+/// `struct cros_ec_command` of the v1 interface.
+#[repr(C)]
+struct CommandV1 {
+    version: u32,
+    command: u32,
+    outdata: *const u8,
+    outsize: u32,
+    indata: *mut u8,
+    insize: u32,
+    result: u32,
+}
+
+/// `struct cros_ec_readmem` of the v1 interface.
+#[repr(C)]
+struct ReadmemV1 {
+    offset: u32,
+    bytes: u32,
+    buffer: *mut u8,
+}
+
+/// `struct cros_ec_command` of the v2 interface, without the trailing `data[]`.
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+struct CommandV2 {
+    version: u32,
+    command: u32,
+    outsize: u32,
+    insize: u32,
+    result: u32,
+}
+
+/// `struct cros_ec_readmem` of the v2 interface.
+#[repr(C)]
+struct ReadmemV2 {
+    offset: u32,
+    bytes: u32,
+    buffer: [u8; memmap::SIZE],
+}
+
+// Make sure the layouts match the kernel's.
+#[cfg(target_pointer_width = "64")]
+const _: () = {
+    assert!(size_of::<CommandV1>() == 40);
+    assert!(offset_of!(CommandV1, outdata) == 8);
+    assert!(offset_of!(CommandV1, outsize) == 16);
+    assert!(offset_of!(CommandV1, indata) == 24);
+    assert!(offset_of!(CommandV1, insize) == 32);
+    assert!(offset_of!(CommandV1, result) == 36);
+    assert!(size_of::<ReadmemV1>() == 16);
+    assert!(offset_of!(ReadmemV1, buffer) == 8);
+};
+#[cfg(target_pointer_width = "32")]
+const _: () = {
+    assert!(size_of::<CommandV1>() == 28);
+    assert!(offset_of!(CommandV1, outdata) == 8);
+    assert!(offset_of!(CommandV1, outsize) == 12);
+    assert!(offset_of!(CommandV1, indata) == 16);
+    assert!(offset_of!(CommandV1, insize) == 20);
+    assert!(offset_of!(CommandV1, result) == 24);
+    assert!(size_of::<ReadmemV1>() == 12);
+    assert!(offset_of!(ReadmemV1, buffer) == 8);
+};
+const _: () = {
+    assert!(size_of::<CommandV2>() == 20);
+    assert!(offset_of!(CommandV2, outsize) == 8);
+    assert!(offset_of!(CommandV2, insize) == 12);
+    assert!(offset_of!(CommandV2, result) == 16);
+    assert!(memmap::SIZE == 255);
+    assert!(size_of::<ReadmemV2>() == 264);
+    assert!(offset_of!(ReadmemV2, buffer) == 8);
+};
+
+/// A v2 `cros_ec_command` followed by room for its payload.
+///
+/// The buffer is made of `u32`s so that the header is correctly aligned.
+struct CommandBuffer {
+    words: Vec<u32>,
+}
+
+impl CommandBuffer {
+    const HEADER: usize = size_of::<CommandV2>();
+
+    fn new(header: CommandV2, request: &[u8], response_len: usize) -> Self {
+        let len = Self::HEADER + request.len().max(response_len);
+        let mut this = Self {
+            words: vec![0; len.div_ceil(4)],
+        };
+        let bytes = this.bytes_mut();
+        bytes[..Self::HEADER].copy_from_slice(bytemuck::bytes_of(&header));
+        bytes[Self::HEADER..][..request.len()].copy_from_slice(request);
+        this
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        bytemuck::cast_slice_mut(&mut self.words)
+    }
+
+    fn header(&self) -> &CommandV2 {
+        bytemuck::from_bytes(&bytemuck::cast_slice(&self.words)[..Self::HEADER])
+    }
+
+    fn data(&self) -> &[u8] {
+        &bytemuck::cast_slice(&self.words)[Self::HEADER..]
+    }
+}
+
+pub(super) fn command_v1(
+    fd: BorrowedFd<'_>,
+    limits: &ProtocolInfo,
+    command: u32,
+    version: u32,
+    request: &[u8],
+    response: &mut [u8],
+) -> nix::Result<Reply> {
+    check_sizes(limits, request, response)?;
+    let mut cmd = CommandV1 {
+        version,
+        command,
+        outdata: request.as_ptr(),
+        outsize: ioctl_size(request.len())?,
+        indata: response.as_mut_ptr(),
+        insize: ioctl_size(response.len())?,
+        result: 0xff,
+    };
+    // SAFETY: `cmd` points to `request` and `response`, which outlive the call, and carries
+    // their lengths. The kernel only reads from `outdata`.
+    let len = unsafe {
+        ioctl(
+            fd,
+            nix::request_code_readwrite!(':', 0, size_of::<CommandV1>()),
+            &raw mut cmd,
+        )?
+    };
+    Ok(Reply {
+        result: cmd.result,
+        len: len.min(response.len()),
+    })
+}
+
+pub(super) fn command_v2(
+    fd: BorrowedFd<'_>,
+    limits: &ProtocolInfo,
+    command: u32,
+    version: u32,
+    request: &[u8],
+    response: &mut [u8],
+) -> nix::Result<Reply> {
+    check_sizes(limits, request, response)?;
+    let mut buf = CommandBuffer::new(
+        CommandV2 {
+            version,
+            command,
+            outsize: ioctl_size(request.len())?,
+            insize: ioctl_size(response.len())?,
+            result: 0xff,
+        },
+        request,
+        response.len(),
+    );
+    // SAFETY: `buf` holds an aligned `cros_ec_command` whose payload has room for both `outsize`
+    // and `insize` bytes, which is all the kernel accesses.
+    let len = unsafe {
+        ioctl(
+            fd,
+            nix::request_code_readwrite!(0xEC, 0, size_of::<CommandV2>()),
+            buf.bytes_mut().as_mut_ptr(),
+        )?
+    };
+
+    // Only copy what the EC actually sent, the rest of the payload still holds the request.
+    let len = len.min(response.len());
+    response[..len].copy_from_slice(&buf.data()[..len]);
+    Ok(Reply {
+        result: buf.header().result,
+        len,
+    })
+}
+
+pub(super) fn readmem_v1(fd: BorrowedFd<'_>, offset: u8, buf: &mut [u8]) -> nix::Result<usize> {
+    if usize::from(offset) + buf.len() > memmap::SIZE {
+        return Err(Errno::EINVAL);
+    }
+    let mut mem = ReadmemV1 {
+        offset: offset.into(),
+        bytes: ioctl_size(buf.len())?,
+        buffer: buf.as_mut_ptr(),
+    };
+    // SAFETY: `mem` points to `buf`, which outlives the call, and carries its length.
+    let len = unsafe {
+        ioctl(
+            fd,
+            nix::request_code_readwrite!(':', 1, size_of::<ReadmemV1>()),
+            &raw mut mem,
+        )?
+    };
+    Ok(len.min(buf.len()))
+}
+
+pub(super) fn readmem_v2(fd: BorrowedFd<'_>, offset: u8, buf: &mut [u8]) -> nix::Result<usize> {
+    if usize::from(offset) + buf.len() > memmap::SIZE {
+        return Err(Errno::EINVAL);
+    }
+    let mut mem = ReadmemV2 {
+        offset: offset.into(),
+        bytes: ioctl_size(buf.len())?,
+        buffer: [0; memmap::SIZE],
+    };
+    // SAFETY: `mem` is a complete `cros_ec_readmem`, and we've checked that the requested range
+    // fits its buffer.
+    let len = unsafe {
+        ioctl(
+            fd,
+            nix::request_code_readwrite!(0xEC, 1, size_of::<ReadmemV2>()),
+            &raw mut mem,
+        )?
+    };
+    let len = len.min(buf.len());
+    buf[..len].copy_from_slice(&mem.buffer[..len]);
+    Ok(len)
+}
+
+/// Selects which events reading from the device returns (v2 only).
+pub(super) fn set_event_mask(fd: BorrowedFd<'_>, mask: u32) -> nix::Result<()> {
+    // SAFETY: The argument is passed by value, no memory is involved.
+    unsafe {
+        ioctl(
+            fd,
+            nix::request_code_none!(0xEC, 2),
+            libc::c_ulong::from(mask),
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,509 @@
+//! Direct host command transport over the EC's LPC I/O ports.
+//!
+//! This talks to the EC without going through the kernel's `cros_ec` driver, which is useful on
+//! kernels that don't have it. It must not be used while the driver is loaded, since both would
+//! race for the same ports.
+
+use std::{
+    fs::File,
+    io,
+    mem::size_of,
+    os::unix::fs::FileExt,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use bytemuck::Zeroable;
+
+use super::{
+    Backend, EcError, Reply,
+    packet::{self, RequestHeader, ResponseHeader},
+};
+use crate::memmap::{self, Id, Region};
+
+/// Data port, returns the result of the last command.
+const ADDR_HOST_DATA: u16 = 0x200;
+/// Command and status port.
+const ADDR_HOST_CMD: u16 = 0x204;
+/// Start of the packet window used by protocol version 3.
+const ADDR_HOST_PACKET: u16 = 0x800;
+/// Size of the packet window.
+const HOST_PACKET_SIZE: usize = 0x100;
+/// Start of the memory-mapped region.
+const ADDR_MEMMAP: u16 = 0x900;
+
+/// Status bits that are set while the EC is busy with a command.
+const STATUS_BUSY_MASK: u8 = STATUS_FROM_HOST | STATUS_PROCESSING;
+const STATUS_FROM_HOST: u8 = 0x02;
+const STATUS_PROCESSING: u8 = 0x04;
+
+/// Written to the command port to start a protocol version 3 command.
+const COMMAND_PROTOCOL_3: u8 = 0xda;
+
+/// How long to wait for the EC to finish a command.
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Access to the I/O ports of the EC.
+pub trait PortIo: Send {
+    /// Reads `buf.len()` consecutive ports, starting at `port`.
+    fn read(&self, port: u16, buf: &mut [u8]) -> io::Result<()>;
+
+    /// Writes `data` to consecutive ports, starting at `port`.
+    fn write(&self, port: u16, data: &[u8]) -> io::Result<()>;
+
+    fn inb(&self, port: u16) -> io::Result<u8> {
+        let mut b = [0];
+        self.read(port, &mut b)?;
+        Ok(b[0])
+    }
+
+    fn outb(&self, port: u16, value: u8) -> io::Result<()> {
+        self.write(port, &[value])
+    }
+}
+
+impl<P: PortIo + ?Sized> PortIo for Box<P> {
+    fn read(&self, port: u16, buf: &mut [u8]) -> io::Result<()> {
+        (**self).read(port, buf)
+    }
+
+    fn write(&self, port: u16, data: &[u8]) -> io::Result<()> {
+        (**self).write(port, data)
+    }
+}
+
+/// Port access through `/dev/port`.
+pub struct DevPort {
+    file: File,
+}
+
+impl DevPort {
+    pub fn open() -> io::Result<Self> {
+        Ok(Self {
+            file: File::options().read(true).write(true).open("/dev/port")?,
+        })
+    }
+}
+
+impl PortIo for DevPort {
+    fn read(&self, port: u16, buf: &mut [u8]) -> io::Result<()> {
+        self.file.read_exact_at(buf, port.into())
+    }
+
+    fn write(&self, port: u16, data: &[u8]) -> io::Result<()> {
+        self.file.write_all_at(data, port.into())
+    }
+}
+
+/// Port access for Microchip MEC ECs, which expose the packet window and memory-mapped region
+/// through an EMI (Embedded Memory Interface) instead of plain I/O ports.
+pub struct MecEmi<P> {
+    ports: P,
+}
+
+impl<P: PortIo> MecEmi<P> {
+    /// Base port of the EMI registers.
+    const BASE: u16 = 0x800;
+    const EC_ADDRESS_B0: u16 = Self::BASE + 2;
+    const EC_ADDRESS_B1: u16 = Self::BASE + 3;
+    const EC_DATA_B0: u16 = Self::BASE + 4;
+    /// Ports in this range are routed through the EMI.
+    const RANGE: std::ops::Range<u16> = 0x800..0xa00;
+    const ACCESS_TYPE_BYTE: u8 = 0;
+
+    pub fn new(ports: P) -> Self {
+        Self { ports }
+    }
+
+    /// Accesses `len` bytes starting at `port` through the EMI, one 32-bit window at a time.
+    ///
+    /// The EMI addresses the EC's memory relative to the start of [`MecEmi::RANGE`].
+    fn access(
+        &self,
+        port: u16,
+        len: usize,
+        mut f: impl FnMut(u16, usize) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let mut i = 0;
+        while i < len {
+            let addr = port - Self::RANGE.start + i as u16;
+            self.ports.outb(
+                Self::EC_ADDRESS_B0,
+                (addr & 0xfc) as u8 | Self::ACCESS_TYPE_BYTE,
+            )?;
+            self.ports
+                .outb(Self::EC_ADDRESS_B1, ((addr >> 8) & 0x7f) as u8)?;
+            for offset in (addr & 3)..4 {
+                if i == len {
+                    break;
+                }
+                f(Self::EC_DATA_B0 + offset, i)?;
+                i += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<P: PortIo> PortIo for MecEmi<P> {
+    fn read(&self, port: u16, buf: &mut [u8]) -> io::Result<()> {
+        if !Self::RANGE.contains(&port) {
+            return self.ports.read(port, buf);
+        }
+        self.access(port, buf.len(), |data_port, i| {
+            buf[i] = self.ports.inb(data_port)?;
+            Ok(())
+        })
+    }
+
+    fn write(&self, port: u16, data: &[u8]) -> io::Result<()> {
+        if !Self::RANGE.contains(&port) {
+            return self.ports.write(port, data);
+        }
+        self.access(port, data.len(), |data_port, i| {
+            self.ports.outb(data_port, data[i])
+        })
+    }
+}
+
+/// [`Backend`] that issues protocol version 3 host commands over LPC I/O ports.
+pub struct Lpc<P> {
+    ports: P,
+}
+
+impl Lpc<Box<dyn PortIo>> {
+    /// Probes for an EC on the LPC bus using `/dev/port`.
+    pub fn open() -> io::Result<Self> {
+        match Lpc::new(Box::new(DevPort::open()?) as Box<dyn PortIo>) {
+            Ok(lpc) => return Ok(lpc),
+            Err(e) => log::debug!("no EC found: {}", e),
+        }
+        // Microchip ECs expose their memory map through the EMI instead. The descriptor of the
+        // first probe has been closed along with it by now.
+        match Lpc::new(Box::new(MecEmi::new(DevPort::open()?)) as Box<dyn PortIo>) {
+            Ok(lpc) => return Ok(lpc),
+            Err(e) => log::debug!("no EC found behind MEC EMI: {}", e),
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no EC found on the LPC bus",
+        ))
+    }
+}
+
+impl<P: PortIo> Lpc<P> {
+    /// Checks that an EC supporting protocol version 3 is reachable through `ports`.
+    pub fn new(ports: P) -> io::Result<Self> {
+        let this = Self { ports };
+        let mut id = Id::zeroed();
+        this.ports.read(
+            ADDR_MEMMAP + u16::from(Id::OFFSET),
+            bytemuck::bytes_of_mut(&mut id),
+        )?;
+        if !id.is_valid() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "EC memory map signature not found",
+            ));
+        }
+        if id.host_cmd_flags & Id::HOST_CMD_FLAG_VERSION_3 == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "EC does not support host command protocol version 3",
+            ));
+        }
+        Ok(this)
+    }
+
+    fn wait_for_ec(&self) -> io::Result<()> {
+        let start = Instant::now();
+        let mut delay = Duration::from_micros(1);
+        while self.ports.inb(ADDR_HOST_CMD)? & STATUS_BUSY_MASK != 0 {
+            if start.elapsed() > TIMEOUT {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "timed out waiting for EC",
+                ));
+            }
+            thread::sleep(delay);
+            delay = (delay * 2).min(Duration::from_millis(1));
+        }
+        Ok(())
+    }
+}
+
+impl<P: PortIo> Backend for Lpc<P> {
+    fn command(
+        &self,
+        command: u32,
+        version: u32,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> io::Result<Reply> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let command = u16::try_from(command).map_err(|_| invalid("command ID out of range"))?;
+        let version = u8::try_from(version).map_err(|_| invalid("command version out of range"))?;
+        if size_of::<RequestHeader>() + request.len() > HOST_PACKET_SIZE {
+            return Err(invalid("request too large for packet window"));
+        }
+
+        let req = packet::encode_request(command, version, request);
+        self.wait_for_ec()?;
+        self.ports.write(ADDR_HOST_PACKET, &req)?;
+        self.ports.outb(ADDR_HOST_CMD, COMMAND_PROTOCOL_3)?;
+        self.wait_for_ec()?;
+
+        let result = self.ports.inb(ADDR_HOST_DATA)?;
+        if result != 0 {
+            return Ok(Reply {
+                result: result.into(),
+                len: 0,
+            });
+        }
+
+        let mut resp = vec![0; size_of::<ResponseHeader>()];
+        self.ports.read(ADDR_HOST_PACKET, &mut resp)?;
+        let len = packet::response_len(&resp);
+        if len > HOST_PACKET_SIZE || len - size_of::<ResponseHeader>() > response.len() {
+            return Ok(Reply::error(EcError::ResponseTooBig));
+        }
+        resp.resize(len, 0);
+        self.ports.read(
+            ADDR_HOST_PACKET + size_of::<ResponseHeader>() as u16,
+            &mut resp[size_of::<ResponseHeader>()..],
+        )?;
+
+        let (header, data) = packet::decode_response(&resp)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        response[..data.len()].copy_from_slice(data);
+        Ok(Reply {
+            result: header.result.into(),
+            len: data.len(),
+        })
+    }
+
+    fn read_memmap(&self, offset: u8, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(memmap::SIZE - usize::from(offset));
+        self.ports
+            .read(ADDR_MEMMAP + u16::from(offset), &mut buf[..len])?;
+        Ok(len)
+    }
+}
+
+/// An in-process stand-in for the EC's I/O ports.
+///
+/// Commands written to the packet window are decoded and handed to a [`Backend`], and its reply is
+/// encoded back into the window, like a real EC would. This allows exercising [`Lpc`] and the
+/// packet codec without hardware.
+pub struct FakePorts<B> {
+    backend: B,
+    state: Mutex<FakeState>,
+}
+
+struct FakeState {
+    packet: [u8; HOST_PACKET_SIZE],
+    result: u8,
+}
+
+impl<B: Backend> FakePorts<B> {
+    pub fn new(backend: B) -> Self {
+        Self {
+            backend,
+            state: Mutex::new(FakeState {
+                packet: [0; HOST_PACKET_SIZE],
+                result: 0,
+            }),
+        }
+    }
+
+    fn run_command(&self, state: &mut FakeState) -> io::Result<()> {
+        let (header, data) = match packet::decode_request(&state.packet) {
+            Ok(req) => req,
+            Err(packet::PacketError::BadChecksum) => {
+                state.result = EcError::InvalidChecksum.code() as u8;
+                return Ok(());
+            }
+            Err(_) => {
+                state.result = EcError::InvalidHeader.code() as u8;
+                return Ok(());
+            }
+        };
+        let mut response = [0; HOST_PACKET_SIZE - size_of::<ResponseHeader>()];
+        let reply = self.backend.command(
+            header.command.into(),
+            header.command_version.into(),
+            data,
+            &mut response,
+        )?;
+        state.result = reply.result as u8;
+        let resp = packet::encode_response(reply.result as u16, &response[..reply.len]);
+        state.packet[..resp.len()].copy_from_slice(&resp);
+        Ok(())
+    }
+}
+
+impl<B: Backend> PortIo for FakePorts<B> {
+    fn read(&self, port: u16, buf: &mut [u8]) -> io::Result<()> {
+        let state = self.state.lock().unwrap();
+        for (i, b) in buf.iter_mut().enumerate() {
+            let port = port + i as u16;
+            *b = match port {
+                ADDR_HOST_DATA => state.result,
+                // Commands complete synchronously, so the EC is never busy.
+                ADDR_HOST_CMD => 0,
+                p if (ADDR_HOST_PACKET..ADDR_MEMMAP).contains(&p) => {
+                    state.packet[usize::from(p - ADDR_HOST_PACKET)]
+                }
+                p if (ADDR_MEMMAP..ADDR_MEMMAP + memmap::SIZE as u16).contains(&p) => {
+                    let mut byte = [0xff];
+                    self.backend
+                        .read_memmap((p - ADDR_MEMMAP) as u8, &mut byte)?;
+                    byte[0]
+                }
+                _ => 0xff,
+            };
+        }
+        Ok(())
+    }
+
+    fn write(&self, port: u16, data: &[u8]) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        for (i, &b) in data.iter().enumerate() {
+            match port + i as u16 {
+                ADDR_HOST_CMD if b == COMMAND_PROTOCOL_3 => self.run_command(&mut state)?,
+                p if (ADDR_HOST_PACKET..ADDR_MEMMAP).contains(&p) => {
+                    state.packet[usize::from(p - ADDR_HOST_PACKET)] = b;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        command::{GetKeyboardBacklight, SetKeyboardBacklight},
+        ec::{
+            EmbeddedController,
+            sim::{SimState, SimulatedEc},
+        },
+        memmap::Switches,
+    };
+
+    /// Emulates a MEC's EMI in front of the EC's address space.
+    struct FakeEmi<P> {
+        ports: P,
+        address: Mutex<u16>,
+    }
+
+    impl<P: PortIo> FakeEmi<P> {
+        fn new(ports: P) -> Self {
+            Self {
+                ports,
+                address: Mutex::new(0),
+            }
+        }
+
+        /// Returns the port behind EMI data register `port`, if it is one.
+        fn data_port(&self, port: u16) -> Option<u16> {
+            let data = MecEmi::<P>::EC_DATA_B0..MecEmi::<P>::EC_DATA_B0 + 4;
+            let address = *self.address.lock().unwrap();
+            data.contains(&port)
+                .then(|| MecEmi::<P>::RANGE.start + (address & !3) + (port - data.start))
+        }
+    }
+
+    impl<P: PortIo> PortIo for FakeEmi<P> {
+        fn read(&self, port: u16, buf: &mut [u8]) -> io::Result<()> {
+            for (i, b) in buf.iter_mut().enumerate() {
+                let port = port + i as u16;
+                *b = match self.data_port(port) {
+                    Some(p) => self.ports.inb(p)?,
+                    None => self.ports.inb(port)?,
+                };
+            }
+            Ok(())
+        }
+
+        fn write(&self, port: u16, data: &[u8]) -> io::Result<()> {
+            for (i, &b) in data.iter().enumerate() {
+                let port = port + i as u16;
+                let mut address = self.address.lock().unwrap();
+                match port {
+                    p if p == MecEmi::<P>::EC_ADDRESS_B0 => {
+                        *address = (*address & 0xff00) | u16::from(b & 0xfc);
+                    }
+                    p if p == MecEmi::<P>::EC_ADDRESS_B1 => {
+                        *address = (*address & 0x00ff) | (u16::from(b & 0x7f) << 8);
+                    }
+                    _ => {
+                        drop(address);
+                        match self.data_port(port) {
+                            Some(p) => self.ports.outb(p, b)?,
+                            None => self.ports.outb(port, b)?,
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn sim() -> SimulatedEc {
+        SimulatedEc::new(SimState {
+            percent: 30,
+            enabled: true,
+            lid_open: false,
+            ..SimState::default()
+        })
+    }
+
+    fn round_trip(ports: impl PortIo + 'static) {
+        let lpc = Lpc::new(ports).unwrap();
+        let ec = EmbeddedController::with_backend(Box::new(lpc)).unwrap();
+        assert_eq!(ec.command(GetKeyboardBacklight).unwrap().percent, 30);
+        ec.command(SetKeyboardBacklight { percent: 75 }).unwrap();
+        assert_eq!(ec.command(GetKeyboardBacklight).unwrap().percent, 75);
+        assert!(!ec.memmap::<Switches>().unwrap().lid_open());
+    }
+
+    #[test]
+    fn protocol_3() {
+        round_trip(FakePorts::new(sim()));
+    }
+
+    #[test]
+    fn mec_emi() {
+        round_trip(MecEmi::new(FakeEmi::new(FakePorts::new(sim()))));
+    }
+
+    #[test]
+    fn mec_emi_addresses_are_relative() {
+        let mec = MecEmi::new(FakeEmi::new(FakePorts::new(sim())));
+        mec.read(ADDR_MEMMAP + 5, &mut [0]).unwrap();
+        assert_eq!(
+            *mec.ports.address.lock().unwrap(),
+            ADDR_MEMMAP + 4 - ADDR_HOST_PACKET
+        );
+    }
+
+    #[test]
+    fn no_ec() {
+        struct Floating;
+        impl PortIo for Floating {
+            fn read(&self, _: u16, buf: &mut [u8]) -> io::Result<()> {
+                buf.fill(0xff);
+                Ok(())
+            }
+            fn write(&self, _: u16, _: &[u8]) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let err = Lpc::new(Floating).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
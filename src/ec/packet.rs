@@ -0,0 +1,235 @@
+//! Encoding and decoding of host command protocol version 3 packets.
+//!
+//! Every packet starts with a header (`struct ec_host_request` or `struct ec_host_response`),
+//! followed by the payload. All bytes of a packet, including the checksum in the header, sum up to
+//! zero.
+
+use std::{error, fmt, mem::size_of};
+
+use bytemuck::{Pod, Zeroable};
+
+/// The `struct_version` of protocol version 3 packets.
+pub const VERSION: u8 = 3;
+
+/// Header of a request packet (`struct ec_host_request`).
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct RequestHeader {
+    pub struct_version: u8,
+    pub checksum: u8,
+    pub command: u16,
+    pub command_version: u8,
+    pub reserved: u8,
+    pub data_len: u16,
+}
+
+/// Header of a response packet (`struct ec_host_response`).
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct ResponseHeader {
+    pub struct_version: u8,
+    pub checksum: u8,
+    pub result: u16,
+    pub data_len: u16,
+    pub reserved: u16,
+}
+
+/// Errors that can occur when decoding a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketError {
+    /// The packet is shorter than its header.
+    MissingHeader,
+    /// The header has a `struct_version` other than 3.
+    UnsupportedVersion(u8),
+    /// The header announces more payload than the packet contains.
+    Truncated { data_len: usize, available: usize },
+    /// The bytes of the packet don't sum up to zero.
+    BadChecksum,
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingHeader => f.write_str("packet is too short to contain a header"),
+            Self::UnsupportedVersion(v) => write!(f, "unsupported packet version {v}"),
+            Self::Truncated {
+                data_len,
+                available,
+            } => write!(
+                f,
+                "packet truncated (header announces {data_len} bytes of data, got {available})"
+            ),
+            Self::BadChecksum => f.write_str("packet checksum mismatch"),
+        }
+    }
+}
+
+impl error::Error for PacketError {}
+
+/// Computes the value of the `checksum` field for a packet whose other bytes are `bytes`.
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes
+        .iter()
+        .fold(0u8, |sum, &b| sum.wrapping_add(b))
+        .wrapping_neg()
+}
+
+/// Encodes a request packet.
+///
+/// Panics if `data` is longer than `u16::MAX` bytes.
+pub fn encode_request(command: u16, command_version: u8, data: &[u8]) -> Vec<u8> {
+    let header = RequestHeader {
+        struct_version: VERSION,
+        checksum: 0,
+        command,
+        command_version,
+        reserved: 0,
+        data_len: data.len().try_into().unwrap(),
+    };
+    finish(bytemuck::bytes_of(&header), data)
+}
+
+/// Encodes a response packet.
+///
+/// Panics if `data` is longer than `u16::MAX` bytes.
+pub fn encode_response(result: u16, data: &[u8]) -> Vec<u8> {
+    let header = ResponseHeader {
+        struct_version: VERSION,
+        checksum: 0,
+        result,
+        data_len: data.len().try_into().unwrap(),
+        reserved: 0,
+    };
+    finish(bytemuck::bytes_of(&header), data)
+}
+
+/// Decodes a request packet, returning its header and payload.
+pub fn decode_request(packet: &[u8]) -> Result<(RequestHeader, &[u8]), PacketError> {
+    let (header, rest) = split::<RequestHeader>(packet)?;
+    if header.struct_version != VERSION {
+        return Err(PacketError::UnsupportedVersion(header.struct_version));
+    }
+    let data = verify(packet, rest, header.data_len)?;
+    Ok((header, data))
+}
+
+/// Decodes a response packet, returning its header and payload.
+pub fn decode_response(packet: &[u8]) -> Result<(ResponseHeader, &[u8]), PacketError> {
+    let (header, rest) = split::<ResponseHeader>(packet)?;
+    if header.struct_version != VERSION {
+        return Err(PacketError::UnsupportedVersion(header.struct_version));
+    }
+    let data = verify(packet, rest, header.data_len)?;
+    Ok((header, data))
+}
+
+/// Returns the total length of the request packet starting with `header`, which must be at least
+/// as long as a [`RequestHeader`].
+pub fn request_len(header: &[u8]) -> usize {
+    let header: RequestHeader = bytemuck::pod_read_unaligned(&header[..size_of::<RequestHeader>()]);
+    size_of::<RequestHeader>() + usize::from(header.data_len)
+}
+
+/// Returns the total length of the response packet starting with `header`, which must be at least
+/// as long as a [`ResponseHeader`].
+pub fn response_len(header: &[u8]) -> usize {
+    let header: ResponseHeader =
+        bytemuck::pod_read_unaligned(&header[..size_of::<ResponseHeader>()]);
+    size_of::<ResponseHeader>() + usize::from(header.data_len)
+}
+
+fn finish(header: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(header.len() + data.len());
+    packet.extend_from_slice(header);
+    packet.extend_from_slice(data);
+    // The checksum is the second byte of both header types.
+    packet[1] = checksum(&packet);
+    packet
+}
+
+fn split<H: Pod>(packet: &[u8]) -> Result<(H, &[u8]), PacketError> {
+    if packet.len() < size_of::<H>() {
+        return Err(PacketError::MissingHeader);
+    }
+    let (header, rest) = packet.split_at(size_of::<H>());
+    Ok((bytemuck::pod_read_unaligned(header), rest))
+}
+
+fn verify<'a>(packet: &[u8], rest: &'a [u8], data_len: u16) -> Result<&'a [u8], PacketError> {
+    let data_len = usize::from(data_len);
+    let data = rest.get(..data_len).ok_or(PacketError::Truncated {
+        data_len,
+        available: rest.len(),
+    })?;
+    let len = packet.len() - rest.len() + data_len;
+    if checksum(&packet[..len]) != 0 {
+        return Err(PacketError::BadChecksum);
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_round_trip() {
+        let packet = encode_request(0x0023, 1, &[42, 7]);
+        assert_eq!(packet.len(), size_of::<RequestHeader>() + 2);
+        assert_eq!(request_len(&packet), packet.len());
+        assert_eq!(packet.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)), 0);
+
+        let (header, data) = decode_request(&packet).unwrap();
+        assert_eq!(header.command, 0x0023);
+        assert_eq!(header.command_version, 1);
+        assert_eq!(data, [42, 7]);
+    }
+
+    #[test]
+    fn response_round_trip() {
+        let packet = encode_response(3, &[1, 2, 3, 4]);
+        assert_eq!(response_len(&packet), packet.len());
+
+        let (header, data) = decode_response(&packet).unwrap();
+        assert_eq!(header.result, 3);
+        assert_eq!(data, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn trailing_bytes_are_ignored() {
+        let mut packet = encode_response(0, &[5]);
+        packet.extend_from_slice(&[0xaa; 8]);
+        assert_eq!(decode_response(&packet).unwrap().1, [5]);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            decode_request(&[3, 0, 0]).unwrap_err(),
+            PacketError::MissingHeader
+        );
+
+        let mut packet = encode_request(1, 0, &[1, 2, 3]);
+        packet[9] ^= 0x10;
+        assert_eq!(
+            decode_request(&packet).unwrap_err(),
+            PacketError::BadChecksum
+        );
+
+        let packet = encode_request(1, 0, &[1, 2, 3]);
+        assert_eq!(
+            decode_request(&packet[..packet.len() - 1]).unwrap_err(),
+            PacketError::Truncated {
+                data_len: 3,
+                available: 2
+            }
+        );
+
+        let mut packet = encode_response(0, &[]);
+        packet[0] = 2;
+        assert_eq!(
+            decode_response(&packet).unwrap_err(),
+            PacketError::UnsupportedVersion(2)
+        );
+    }
+}
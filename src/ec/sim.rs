@@ -0,0 +1,597 @@
+//! An Embedded Controller simulated in memory.
+//!
+//! [`SimulatedEc`] answers host commands like a Framework laptop's EC would, using the same
+//! command structs as the rest of the crate. It backs `keylightd --simulate` and the
+//! `keylightd-ec-sim` binary, and is used to test the daemon without hardware.
+
+use std::{collections::VecDeque, io, sync::Mutex, time::Instant};
+#[cfg(test)]
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use bytemuck::{AnyBitPattern, NoUninit, Zeroable};
+
+use crate::command::{
+    Cmd, EcImage, Feature, GetChipInfoResponse, GetCmdVersions, GetCmdVersionsResponse,
+    GetCmdVersionsV1, GetFeaturesResponse, GetKeyboardBacklightResponse, GetProtocolInfoResponse,
+    GetUptimeInfoResponse, GetVersionResponse, Hello, HelloResponse, LedBrightnesses, LedColor,
+    LedControl, LedControlResponse, LedFlags, LedId, PWM_MAX_DUTY, PwmGetDuty, PwmGetDutyResponse,
+    PwmSetDuty, PwmType, ResetFlag, ResetFlags, SetKeyboardBacklight,
+};
+
+use crate::{
+    event::{EventType, HostEvent},
+    memmap::{self, AmbientLight, Battery, Id, Region, Switches},
+};
+
+use super::{Backend, EcError, Reply};
+
+/// State of a single LED in a [`SimulatedEc`].
+#[derive(Debug, Clone, Copy)]
+pub struct LedState {
+    /// Whether the LED is under automatic EC control.
+    pub auto: bool,
+    /// Manually set brightness, used when `auto` is `false`.
+    pub brightness: LedBrightnesses,
+}
+
+impl Default for LedState {
+    fn default() -> Self {
+        Self {
+            auto: true,
+            brightness: LedBrightnesses::default(),
+        }
+    }
+}
+
+/// Observable state of a [`SimulatedEc`].
+#[derive(Debug, Clone)]
+pub struct SimState {
+    pub percent: u8,
+    pub enabled: bool,
+    /// The PWM duty cycle of the keyboard backlight if it was set with `EC_CMD_PWM_SET_DUTY`, or
+    /// `None` if it was set in percent.
+    pub duty: Option<u16>,
+    pub leds: [LedState; LedId::COUNT],
+    pub lid_open: bool,
+    pub ac_present: bool,
+    /// Remaining battery charge in percent.
+    pub battery: u8,
+    /// Ambient light sensor reading in lux.
+    pub lux: u16,
+    /// When the simulated EC last booted.
+    pub booted: Instant,
+    /// Why the simulated EC last booted.
+    pub reset_flags: ResetFlags,
+}
+
+impl Default for SimState {
+    fn default() -> Self {
+        Self {
+            percent: 0,
+            enabled: false,
+            duty: None,
+            leds: Default::default(),
+            lid_open: true,
+            ac_present: true,
+            battery: 100,
+            lux: 100,
+            booted: Instant::now(),
+            reset_flags: ResetFlags(ResetFlag::POWER_ON.mask()),
+        }
+    }
+}
+
+impl SimState {
+    /// Sets the keyboard backlight brightness in percent, like `EC_CMD_PWM_SET_KEYBOARD_BACKLIGHT`.
+    pub fn set_percent(&mut self, percent: u8) {
+        self.percent = percent;
+        self.enabled = percent > 0;
+        self.duty = None;
+    }
+
+    /// Returns the PWM duty cycle of the keyboard backlight.
+    pub fn duty(&self) -> u16 {
+        self.duty
+            .unwrap_or((u32::from(self.percent) * u32::from(PWM_MAX_DUTY) / 100) as u16)
+    }
+
+    fn set_duty(&mut self, duty: u16) {
+        // The EC reports the brightness rounded to the nearest percent.
+        self.percent =
+            ((u32::from(duty) * 100 + u32::from(PWM_MAX_DUTY) / 2) / u32::from(PWM_MAX_DUTY)) as u8;
+        self.enabled = duty > 0;
+        self.duty = Some(duty);
+    }
+
+    /// Renders the memory-mapped region as the EC would expose it.
+    fn memmap(&self) -> [u8; memmap::SIZE] {
+        fn put<R: Region>(map: &mut [u8], value: R) {
+            let bytes = bytemuck::bytes_of(&value);
+            map[usize::from(R::OFFSET)..][..bytes.len()].copy_from_slice(bytes);
+        }
+
+        let mut map = [0; memmap::SIZE];
+        let mut id = Id::zeroed();
+        id.magic = *b"EC";
+        id.id_version = 1;
+        id.battery_version = 1;
+        id.switches_version = 1;
+        id.host_cmd_flags = Id::HOST_CMD_FLAG_VERSION_3;
+        put(&mut map, id);
+        put(
+            &mut map,
+            Switches(if self.lid_open { Switches::LID_OPEN } else { 0 }),
+        );
+
+        let mut battery = Battery::zeroed();
+        battery.flags = Battery::BATT_PRESENT;
+        if self.ac_present {
+            battery.flags |= Battery::AC_PRESENT;
+            if self.battery < 100 {
+                battery.flags |= Battery::CHARGING;
+            }
+        } else {
+            battery.flags |= Battery::DISCHARGING;
+        }
+        battery.count = 1;
+        battery.voltage = 15400;
+        battery.design_capacity = 3572;
+        battery.design_voltage = 15400;
+        battery.last_full_capacity = 3572;
+        battery.capacity = 3572 * u32::from(self.battery.min(100)) / 100;
+        put(&mut map, battery);
+        put(&mut map, AmbientLight { lux: [self.lux, 0] });
+        map
+    }
+}
+
+/// An in-memory [`Backend`] that behaves like a Framework laptop's EC.
+///
+/// It keeps track of the keyboard backlight and LED state, and answers the commands keylightd
+/// uses, which allows running the daemon on machines without a ChromiumOS EC.
+#[derive(Default)]
+pub struct SimulatedEc {
+    state: Mutex<SimState>,
+    events: Mutex<PendingEvents>,
+    /// Commands removed with [`SimulatedEc::disable_command`].
+    disabled: Mutex<Vec<Cmd>>,
+}
+
+/// Events raised with [`SimulatedEc::raise`] that haven't been read yet.
+#[derive(Default)]
+struct PendingEvents {
+    /// The event types selected with [`Backend::set_event_mask`].
+    mask: u32,
+    queue: VecDeque<Vec<u8>>,
+}
+
+impl SimulatedEc {
+    pub fn new(state: SimState) -> Self {
+        Self {
+            state: Mutex::new(state),
+            events: Mutex::default(),
+            disabled: Mutex::default(),
+        }
+    }
+
+    /// Makes the simulated EC behave like firmware that doesn't implement `cmd`.
+    pub fn disable_command(&self, cmd: Cmd) {
+        self.disabled.lock().unwrap().push(cmd);
+    }
+
+    /// Returns the bitmask of versions of `command` that are currently supported.
+    fn versions(&self, command: u32) -> Option<u32> {
+        let disabled = self.disabled.lock().unwrap();
+        if disabled.iter().any(|&cmd| cmd as u32 == command) {
+            return None;
+        }
+        supported_versions(command)
+    }
+
+    /// Returns a snapshot of the current state.
+    pub fn state(&self) -> SimState {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// Modifies the state, for example to simulate a lid being closed.
+    pub fn update(&self, f: impl FnOnce(&mut SimState)) {
+        f(&mut self.state.lock().unwrap());
+    }
+
+    /// Simulates the EC rebooting, which restarts its uptime and loses the backlight and LED
+    /// settings.
+    pub fn reset(&self, flags: ResetFlags) {
+        let mut state = self.state.lock().unwrap();
+        state.set_percent(0);
+        state.leds = Default::default();
+        state.booted = Instant::now();
+        state.reset_flags = flags;
+    }
+
+    /// Raises a host event, which is delivered through [`Backend::read_event`] if host events
+    /// were subscribed to.
+    ///
+    /// This doesn't change the state, so it's up to the caller to keep the two consistent.
+    pub fn raise(&self, event: HostEvent) {
+        let mut events = self.events.lock().unwrap();
+        if events.mask & EventType::HOST_EVENT.mask() != 0 {
+            let mut raw = vec![EventType::HOST_EVENT.code()];
+            raw.extend_from_slice(&(event.mask() as u32).to_le_bytes());
+            events.queue.push_back(raw);
+        }
+    }
+}
+
+impl Backend for SimulatedEc {
+    fn command(
+        &self,
+        command: u32,
+        version: u32,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> io::Result<Reply> {
+        let Some(versions) = self.versions(command) else {
+            return Ok(Reply::error(EcError::InvalidCommand));
+        };
+        // The version comes from the client, so it may be out of range for the mask.
+        if 1u32
+            .checked_shl(version)
+            .is_none_or(|bit| versions & bit == 0)
+        {
+            return Ok(Reply::error(EcError::InvalidVersion));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let len = match command {
+            c if c == Cmd::Hello as u32 => {
+                let Some(req) = decode::<Hello>(request) else {
+                    return Ok(Reply::error(EcError::RequestTruncated));
+                };
+                encode(
+                    response,
+                    HelloResponse {
+                        out_data: req.in_data.wrapping_add(0x01020304),
+                    },
+                )
+            }
+            c if c == Cmd::GetVersion as u32 => {
+                let mut resp = GetVersionResponse::zeroed();
+                copy_str(&mut resp.version_string_ro, "keylightd-sim-ro");
+                copy_str(&mut resp.version_string_rw, "keylightd-sim-rw");
+                resp.current_image = EcImage::RW.code();
+                encode(response, resp)
+            }
+            c if c == Cmd::GetBuildInfo as u32 => {
+                let info = concat!("keylightd-sim ", env!("CARGO_PKG_VERSION"));
+                let len = info.len().min(response.len());
+                response[..len].copy_from_slice(&info.as_bytes()[..len]);
+                len
+            }
+            c if c == Cmd::GetChipInfo as u32 => {
+                let mut resp = GetChipInfoResponse::zeroed();
+                copy_str(&mut resp.vendor, "keylightd");
+                copy_str(&mut resp.name, "simulated EC");
+                copy_str(&mut resp.revision, "0");
+                encode(response, resp)
+            }
+            c if c == Cmd::GetCmdVersions as u32 => {
+                let cmd = match version {
+                    0 => decode::<GetCmdVersions>(request).map(|req| u32::from(req.cmd)),
+                    _ => decode::<GetCmdVersionsV1>(request).map(|req| u32::from(req.cmd)),
+                };
+                let Some(cmd) = cmd else {
+                    return Ok(Reply::error(EcError::RequestTruncated));
+                };
+                let Some(version_mask) = self.versions(cmd) else {
+                    return Ok(Reply::error(EcError::InvalidParam));
+                };
+                encode(response, GetCmdVersionsResponse { version_mask })
+            }
+            c if c == Cmd::GetFeatures as u32 => {
+                let features = [
+                    Feature::FLASH,
+                    Feature::PWM_KEYB,
+                    Feature::LED,
+                    Feature::KEYB,
+                    Feature::HOST_EVENTS,
+                    Feature::CHARGER,
+                    Feature::BATTERY,
+                ];
+                let mut resp = GetFeaturesResponse::zeroed();
+                for feature in features {
+                    resp.insert(feature);
+                }
+                encode(response, resp)
+            }
+            c if c == Cmd::GetProtocolInfo as u32 => encode(
+                response,
+                GetProtocolInfoResponse {
+                    protocol_versions: 1 << 3,
+                    max_request_packet_size: 0x100,
+                    max_response_packet_size: 0x100,
+                    flags: 0,
+                },
+            ),
+            c if c == Cmd::GetUptimeInfo as u32 => {
+                let mut resp = GetUptimeInfoResponse::zeroed();
+                resp.time_since_ec_boot_ms = state.booted.elapsed().as_millis() as u32;
+                resp.ec_reset_flags = state.reset_flags.0;
+                encode(response, resp)
+            }
+            c if c == Cmd::GetKeyboardBacklight as u32 => encode(
+                response,
+                GetKeyboardBacklightResponse {
+                    percent: state.percent,
+                    enabled: state.enabled.into(),
+                },
+            ),
+            c if c == Cmd::SetKeyboardBacklight as u32 => {
+                let Some(req) = decode::<SetKeyboardBacklight>(request) else {
+                    return Ok(Reply::error(EcError::RequestTruncated));
+                };
+                if req.percent > 100 {
+                    return Ok(Reply::error(EcError::InvalidParam));
+                }
+                log::trace!("simulated EC: keyboard backlight at {}%", req.percent);
+                state.set_percent(req.percent);
+                0
+            }
+            c if c == Cmd::PwmSetDuty as u32 => {
+                let Some(req) = decode::<PwmSetDuty>(request) else {
+                    return Ok(Reply::error(EcError::RequestTruncated));
+                };
+                // The only PWM output of the simulated EC is the keyboard backlight.
+                if req.pwm_type != PwmType::KB_LIGHT {
+                    return Ok(Reply::error(EcError::InvalidParam));
+                }
+                log::trace!("simulated EC: keyboard backlight duty at {}", req.duty);
+                state.set_duty(req.duty);
+                0
+            }
+            c if c == Cmd::PwmGetDuty as u32 => {
+                let Some(req) = decode::<PwmGetDuty>(request) else {
+                    return Ok(Reply::error(EcError::RequestTruncated));
+                };
+                if req.pwm_type != PwmType::KB_LIGHT {
+                    return Ok(Reply::error(EcError::InvalidParam));
+                }
+                encode(response, PwmGetDutyResponse { duty: state.duty() })
+            }
+            c if c == Cmd::LedControl as u32 => {
+                let Some(req) = decode::<LedControl>(request) else {
+                    return Ok(Reply::error(EcError::RequestTruncated));
+                };
+                let Some(led) = state.leds.get_mut(req.led_id.index()) else {
+                    return Ok(Reply::error(EcError::InvalidParam));
+                };
+                let mut resp = LedControlResponse::zeroed();
+                if req.flags.contains(LedFlags::QUERY) {
+                    // Report a single-color white LED, like the Framework's power LED.
+                    resp.brightness = LedBrightnesses::single(LedColor::WHITE, 100);
+                } else if req.flags.contains(LedFlags::AUTO) {
+                    log::debug!("simulated EC: LED {:?} set to auto", req.led_id);
+                    led.auto = true;
+                } else {
+                    log::debug!(
+                        "simulated EC: LED {:?} set to {:?}",
+                        req.led_id,
+                        req.brightness
+                    );
+                    led.auto = false;
+                    led.brightness = req.brightness;
+                }
+                encode(response, resp)
+            }
+            _ => return Ok(Reply::error(EcError::InvalidCommand)),
+        };
+        Ok(Reply::success(len))
+    }
+
+    fn set_event_mask(&self, mask: u32) -> io::Result<()> {
+        self.events.lock().unwrap().mask = mask;
+        Ok(())
+    }
+
+    fn read_event(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(event) = self.events.lock().unwrap().queue.pop_front() else {
+            return Err(io::ErrorKind::WouldBlock.into());
+        };
+        let len = event.len().min(buf.len());
+        buf[..len].copy_from_slice(&event[..len]);
+        Ok(len)
+    }
+
+    fn read_memmap(&self, offset: u8, buf: &mut [u8]) -> io::Result<usize> {
+        let map = self.state.lock().unwrap().memmap();
+        let src = map.get(usize::from(offset)..).unwrap_or_default();
+        let len = src.len().min(buf.len());
+        buf[..len].copy_from_slice(&src[..len]);
+        Ok(len)
+    }
+}
+
+/// A [`SimulatedEc`] that can be made slow or busy, to test how callers deal with that.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct FaultyEc {
+    pub(crate) sim: SimulatedEc,
+    /// How long every command takes.
+    latency: Mutex<Duration>,
+    /// Number of upcoming commands that fail with `EC_RES_BUSY`.
+    busy: AtomicU32,
+    /// The most response bytes to send back.
+    max_response: Mutex<Option<usize>>,
+}
+
+#[cfg(test)]
+impl FaultyEc {
+    /// Connects an [`EmbeddedController`] to a new `FaultyEc`, which starts out without faults.
+    ///
+    /// [`EmbeddedController`]: super::EmbeddedController
+    pub(crate) fn connect() -> (Arc<Self>, super::EmbeddedController) {
+        let ec = Arc::new(Self::default());
+        let controller = super::EmbeddedController::with_backend(Box::new(ec.clone())).unwrap();
+        (ec, controller)
+    }
+
+    pub(crate) fn set_latency(&self, latency: Duration) {
+        *self.latency.lock().unwrap() = latency;
+    }
+
+    /// Makes the next `count` commands fail with `EC_RES_BUSY`.
+    pub(crate) fn fail_busy(&self, count: u32) {
+        self.busy.store(count, Ordering::Relaxed);
+    }
+
+    /// Cuts off the responses to all further commands after `len` bytes.
+    pub(crate) fn truncate_responses(&self, len: usize) {
+        *self.max_response.lock().unwrap() = Some(len);
+    }
+}
+
+#[cfg(test)]
+impl Backend for FaultyEc {
+    fn command(
+        &self,
+        command: u32,
+        version: u32,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> io::Result<Reply> {
+        thread::sleep(*self.latency.lock().unwrap());
+        let busy = self
+            .busy
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+        if busy.is_ok() {
+            return Ok(Reply::error(EcError::Busy));
+        }
+        let mut reply = self.sim.command(command, version, request, response)?;
+        if let Some(max) = *self.max_response.lock().unwrap() {
+            reply.len = reply.len.min(max);
+        }
+        Ok(reply)
+    }
+
+    fn read_memmap(&self, offset: u8, buf: &mut [u8]) -> io::Result<usize> {
+        self.sim.read_memmap(offset, buf)
+    }
+
+    fn set_event_mask(&self, mask: u32) -> io::Result<()> {
+        self.sim.set_event_mask(mask)
+    }
+
+    fn read_event(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.sim.read_event(buf)
+    }
+}
+
+/// Returns the bitmask of versions the simulated EC supports for `command`, or `None` if it doesn't
+/// implement the command.
+fn supported_versions(command: u32) -> Option<u32> {
+    const COMMANDS: &[(Cmd, u32)] = &[
+        (Cmd::Hello, 0b1),
+        (Cmd::GetVersion, 0b1),
+        (Cmd::GetBuildInfo, 0b1),
+        (Cmd::GetChipInfo, 0b1),
+        (Cmd::GetCmdVersions, 0b11),
+        (Cmd::GetProtocolInfo, 0b1),
+        (Cmd::GetFeatures, 0b1),
+        (Cmd::GetKeyboardBacklight, 0b1),
+        (Cmd::SetKeyboardBacklight, 0b1),
+        (Cmd::PwmSetDuty, 0b1),
+        (Cmd::PwmGetDuty, 0b1),
+        // Like on the real EC, only version 1 of this command is supported.
+        (Cmd::LedControl, 0b10),
+        (Cmd::GetUptimeInfo, 0b1),
+    ];
+
+    COMMANDS
+        .iter()
+        .find(|(cmd, _)| *cmd as u32 == command)
+        .map(|&(_, versions)| versions)
+}
+
+fn decode<T: AnyBitPattern>(request: &[u8]) -> Option<T> {
+    bytemuck::try_pod_read_unaligned(request).ok()
+}
+
+fn encode<T: NoUninit>(response: &mut [u8], value: T) -> usize {
+    let bytes = bytemuck::bytes_of(&value);
+    let len = bytes.len().min(response.len());
+    response[..len].copy_from_slice(&bytes[..len]);
+    len
+}
+
+fn copy_str(dest: &mut [u8], s: &str) {
+    let len = s.len().min(dest.len() - 1);
+    dest[..len].copy_from_slice(&s.as_bytes()[..len]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(sim: &SimulatedEc, cmd: Cmd, version: u32, request: &[u8]) -> Reply {
+        sim.command(cmd as u32, version, request, &mut [0; 64])
+            .unwrap()
+    }
+
+    #[test]
+    fn out_of_range_version() {
+        let sim = SimulatedEc::default();
+        for version in [1, 31, 32, 255, u32::MAX] {
+            let reply = command(&sim, Cmd::Hello, version, &[0; 4]);
+            assert_eq!(reply.result, EcError::InvalidVersion.code());
+        }
+    }
+
+    #[test]
+    fn invalid_requests() {
+        let sim = SimulatedEc::default();
+        let reply = command(&sim, Cmd::SetKeyboardBacklight, 0, &[101]);
+        assert_eq!(reply.result, EcError::InvalidParam.code());
+        let reply = command(&sim, Cmd::Hello, 0, &[0; 2]);
+        assert_eq!(reply.result, EcError::RequestTruncated.code());
+        let reply = sim.command(0x7fff, 0, &[], &mut []).unwrap();
+        assert_eq!(reply.result, EcError::InvalidCommand.code());
+    }
+
+    #[test]
+    fn disabled_command() {
+        let sim = SimulatedEc::default();
+        sim.disable_command(Cmd::PwmGetDuty);
+        let reply = command(&sim, Cmd::PwmGetDuty, 0, &[1, 0]);
+        assert_eq!(reply.result, EcError::InvalidCommand.code());
+
+        let request = bytemuck::bytes_of(&GetCmdVersionsV1 {
+            cmd: Cmd::PwmGetDuty as u16,
+        });
+        let reply = command(&sim, Cmd::GetCmdVersions, 1, request);
+        assert_eq!(reply.result, EcError::InvalidParam.code());
+    }
+
+    #[test]
+    fn events_need_subscription() {
+        let sim = SimulatedEc::default();
+        sim.raise(HostEvent::LID_OPEN);
+        let mut buf = [0; 16];
+        let err = sim.read_event(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        sim.set_event_mask(EventType::HOST_EVENT.mask()).unwrap();
+        sim.raise(HostEvent::LID_OPEN);
+        let len = sim.read_event(&mut buf).unwrap();
+        assert_eq!(buf[0], EventType::HOST_EVENT.code());
+        assert_eq!(
+            buf[1..len],
+            (HostEvent::LID_OPEN.mask() as u32).to_le_bytes()
+        );
+    }
+}
@@ -0,0 +1,192 @@
+//! Transport that forwards host commands over a Unix socket, to `keylightd-ec-sim`.
+//!
+//! Every message from the client starts with a message type byte:
+//!
+//! - [`MSG_COMMAND`] is followed by a protocol version 3 request packet, and the server answers
+//!   with a response packet.
+//! - [`MSG_READ_MEMMAP`] is followed by an offset and a length byte, and the server answers with a
+//!   length byte, followed by that many bytes of the memory-mapped region.
+//! - [`MSG_SUBSCRIBE`] is followed by a little-endian `u32` event mask, and turns the connection
+//!   into an event stream. The server acknowledges it with a single zero byte, and then sends
+//!   every event as a length byte, followed by the event type byte and the event data.
+
+use std::{
+    io::{self, Read, Write},
+    mem::size_of,
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::UnixStream,
+    },
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use super::{
+    Backend, EcError, Reply,
+    packet::{self, RequestHeader, ResponseHeader},
+};
+
+pub const MSG_COMMAND: u8 = 1;
+pub const MSG_READ_MEMMAP: u8 = 2;
+pub const MSG_SUBSCRIBE: u8 = 3;
+
+/// Reads a request packet from `r`.
+pub fn read_request(r: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; size_of::<RequestHeader>()];
+    r.read_exact(&mut buf)?;
+    buf.resize(packet::request_len(&buf), 0);
+    r.read_exact(&mut buf[size_of::<RequestHeader>()..])?;
+    Ok(buf)
+}
+
+/// Reads a response packet from `r`.
+pub fn read_response(r: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; size_of::<ResponseHeader>()];
+    r.read_exact(&mut buf)?;
+    buf.resize(packet::response_len(&buf), 0);
+    r.read_exact(&mut buf[size_of::<ResponseHeader>()..])?;
+    Ok(buf)
+}
+
+/// Encodes an event for sending over an event stream.
+///
+/// Panics if `data` is longer than 254 bytes.
+pub fn encode_event(event_type: u8, data: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(data.len() + 2);
+    frame.push(u8::try_from(data.len() + 1).unwrap());
+    frame.push(event_type);
+    frame.extend_from_slice(data);
+    frame
+}
+
+/// [`Backend`] that talks to an EC simulator listening on a Unix socket.
+pub struct Socket {
+    path: PathBuf,
+    stream: Mutex<UnixStream>,
+    events: Mutex<Option<EventStream>>,
+}
+
+struct EventStream {
+    stream: UnixStream,
+    /// Bytes received, but not yet returned as an event.
+    buf: Vec<u8>,
+}
+
+impl Socket {
+    pub fn connect(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let stream = UnixStream::connect(path)?;
+        log::debug!("connected to EC socket {}", path.display());
+        Ok(Self {
+            path: path.to_path_buf(),
+            stream: Mutex::new(stream),
+            events: Mutex::new(None),
+        })
+    }
+}
+
+impl Backend for Socket {
+    fn command(
+        &self,
+        command: u32,
+        version: u32,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> io::Result<Reply> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let command = u16::try_from(command).map_err(|_| invalid("command ID out of range"))?;
+        let version = u8::try_from(version).map_err(|_| invalid("command version out of range"))?;
+
+        let mut msg = vec![MSG_COMMAND];
+        msg.extend(packet::encode_request(command, version, request));
+
+        let mut stream = self.stream.lock().unwrap();
+        stream.write_all(&msg)?;
+        let resp = read_response(&mut *stream)?;
+        drop(stream);
+
+        let (header, data) = packet::decode_response(&resp)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if data.len() > response.len() {
+            return Ok(Reply::error(EcError::ResponseTooBig));
+        }
+        response[..data.len()].copy_from_slice(data);
+        Ok(Reply {
+            result: header.result.into(),
+            len: data.len(),
+        })
+    }
+
+    fn read_memmap(&self, offset: u8, buf: &mut [u8]) -> io::Result<usize> {
+        let len = u8::try_from(buf.len()).unwrap_or(u8::MAX);
+        let mut stream = self.stream.lock().unwrap();
+        stream.write_all(&[MSG_READ_MEMMAP, offset, len])?;
+        let mut n = [0];
+        stream.read_exact(&mut n)?;
+        let n = usize::from(n[0]).min(buf.len());
+        stream.read_exact(&mut buf[..n])?;
+        Ok(n)
+    }
+
+    fn set_event_mask(&self, mask: u32) -> io::Result<()> {
+        // Events are delivered over a separate connection, so that they can't get mixed up with
+        // command responses.
+        let mut stream = UnixStream::connect(&self.path)?;
+        stream.write_all(&[MSG_SUBSCRIBE])?;
+        stream.write_all(&mask.to_le_bytes())?;
+        let mut ack = [0xff];
+        stream.read_exact(&mut ack)?;
+        if ack[0] != 0 {
+            return Err(io::Error::other("EC simulator rejected event subscription"));
+        }
+        stream.set_nonblocking(true)?;
+        *self.events.lock().unwrap() = Some(EventStream {
+            stream,
+            buf: Vec::new(),
+        });
+        Ok(())
+    }
+
+    fn read_event(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut events = self.events.lock().unwrap();
+        let Some(events) = events.as_mut() else {
+            return Err(io::ErrorKind::WouldBlock.into());
+        };
+
+        // Drain the socket, since the daemon only gets woken up again when new data arrives.
+        let mut chunk = [0; 256];
+        loop {
+            match events.stream.read(&mut chunk) {
+                Ok(0) => {
+                    if events.buf.is_empty() {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    break;
+                }
+                Ok(n) => events.buf.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let Some((&len, rest)) = events.buf.split_first() else {
+            return Err(io::ErrorKind::WouldBlock.into());
+        };
+        let len = usize::from(len);
+        if rest.len() < len {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let n = len.min(buf.len());
+        buf[..n].copy_from_slice(&rest[..n]);
+        events.buf.drain(..1 + len);
+        Ok(n)
+    }
+
+    fn event_fd(&self) -> Option<RawFd> {
+        self.events
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|events| events.stream.as_raw_fd())
+    }
+}
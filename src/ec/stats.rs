@@ -0,0 +1,191 @@
+//! Per-command latency statistics.
+
+use std::{fmt, time::Duration};
+
+/// Number of sub-buckets per power of two. Percentiles are accurate to within 1/4 of their
+/// magnitude.
+const SUB_BUCKETS: usize = 4;
+
+/// Enough buckets for latencies of up to 2^32 µs (more than an hour).
+const BUCKETS: usize = 32 * SUB_BUCKETS;
+
+/// A log-linear histogram of latencies with microsecond resolution.
+#[derive(Clone)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    max: Duration,
+}
+
+impl Histogram {
+    pub const fn new() -> Self {
+        Self {
+            buckets: [0; BUCKETS],
+            count: 0,
+            max: Duration::ZERO,
+        }
+    }
+
+    pub fn record(&mut self, latency: Duration) {
+        let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        self.buckets[Self::bucket(micros)] += 1;
+        self.count += 1;
+        self.max = self.max.max(latency);
+    }
+
+    /// Returns the number of recorded latencies.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Returns the largest recorded latency.
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// Returns an upper bound for the latency below which `percent` percent of the recorded
+    /// latencies fall.
+    pub fn percentile(&self, percent: f64) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+        let rank = ((self.count as f64 * percent / 100.0).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            // The last bucket also holds all larger latencies, so it has no upper bound.
+            if seen >= rank && i < BUCKETS - 1 {
+                return Duration::from_micros(Self::upper_bound(i)).min(self.max);
+            }
+        }
+        self.max
+    }
+
+    fn bucket(micros: u64) -> usize {
+        if micros < SUB_BUCKETS as u64 {
+            return micros as usize;
+        }
+        let log = 63 - micros.leading_zeros() as usize;
+        let sub = (micros >> (log - 2)) as usize & (SUB_BUCKETS - 1);
+        ((log - 1) * SUB_BUCKETS + sub).min(BUCKETS - 1)
+    }
+
+    /// Returns the largest latency (in µs) that falls into bucket `i`.
+    fn upper_bound(i: usize) -> u64 {
+        if i < SUB_BUCKETS {
+            return i as u64;
+        }
+        let log = i / SUB_BUCKETS + 1;
+        let sub = (i % SUB_BUCKETS) as u64;
+        ((SUB_BUCKETS as u64 + sub + 1) << (log - 2)) - 1
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Histogram")
+            .field("count", &self.count)
+            .field("p50", &self.percentile(50.0))
+            .field("p99", &self.percentile(99.0))
+            .field("max", &self.max)
+            .finish()
+    }
+}
+
+/// Statistics about the transactions of one EC command.
+#[derive(Debug, Clone, Default)]
+pub struct CommandStats {
+    /// How long the EC took to answer, including failed transactions.
+    pub latency: Histogram,
+    /// The number of transactions that failed, either because the EC returned an error or
+    /// because it could not be reached.
+    pub errors: u64,
+}
+
+impl CommandStats {
+    pub(crate) fn record(&mut self, latency: Duration, ok: bool) {
+        self.latency.record(latency);
+        if !ok {
+            self.errors += 1;
+        }
+    }
+}
+
+impl fmt::Display for CommandStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} sent, {} failed, p50 {:?}, p99 {:?}, max {:?}",
+            self.latency.count(),
+            self.errors,
+            self.latency.percentile(50.0),
+            self.latency.percentile(99.0),
+            self.latency.max(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_cover_their_latencies() {
+        for micros in (0..10_000).chain([u32::MAX as u64 - 1, u32::MAX as u64]) {
+            let bucket = Histogram::bucket(micros);
+            assert!(micros <= Histogram::upper_bound(bucket), "{micros} µs");
+            if bucket > 0 {
+                assert!(micros > Histogram::upper_bound(bucket - 1), "{micros} µs");
+            }
+        }
+        assert_eq!(Histogram::bucket(u64::MAX), BUCKETS - 1);
+    }
+
+    #[test]
+    fn percentiles() {
+        let mut histogram = Histogram::new();
+        assert_eq!(histogram.percentile(50.0), Duration::ZERO);
+        for micros in 1..=100 {
+            histogram.record(Duration::from_micros(micros));
+        }
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.max(), Duration::from_micros(100));
+
+        for (percent, exact) in [(0.0, 1), (50.0, 50), (99.0, 99), (100.0, 100)] {
+            let bound = histogram.percentile(percent).as_micros() as u64;
+            assert!(bound >= exact, "p{percent}: {bound} µs");
+            assert!(
+                bound <= exact + exact / SUB_BUCKETS as u64,
+                "p{percent}: {bound} µs"
+            );
+        }
+    }
+
+    #[test]
+    fn percentiles_are_capped_at_max() {
+        let mut histogram = Histogram::new();
+        histogram.record(Duration::from_micros(1000));
+        assert_eq!(histogram.percentile(99.0), Duration::from_micros(1000));
+        histogram.record(Duration::from_secs(100_000));
+        assert_eq!(histogram.percentile(100.0), Duration::from_secs(100_000));
+    }
+
+    #[test]
+    fn command_stats() {
+        let mut stats = CommandStats::default();
+        stats.record(Duration::from_micros(40), true);
+        stats.record(Duration::from_micros(2000), false);
+        assert_eq!(stats.latency.count(), 2);
+        assert_eq!(stats.errors, 1);
+        assert_eq!(
+            stats.to_string(),
+            "2 sent, 1 failed, p50 47µs, p99 2ms, max 2ms"
+        );
+    }
+}
@@ -0,0 +1,902 @@
+//! Recording and replaying of EC traffic.
+//!
+//! A trace is a text file with one line per transaction:
+//!
+//! ```text
+//! +0.000412 0.000035 cmd 0x0023 v0 req=32 result=0 resp=
+//! +0.000530 0.000004 memmap 0x30 len=1 data=01
+//! +0.000981 0.000051 cmd 0x0022 v0 req= error: Connection reset by peer
+//! +0.001204 0.000009 event-mask 0x00000082
+//! +2.318870 0.000012 event data=0101000000
+//! +2.318911 0.000003 event none
+//! ```
+//!
+//! The first two columns are the time since the start of the recording and the duration of the
+//! transaction, in seconds. Payloads are hex-encoded. Lines starting with `#` are comments.
+
+use std::{
+    collections::VecDeque,
+    fmt::{self, Debug},
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    mem::size_of,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+#[cfg(unix)]
+use std::{
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::UnixStream,
+    },
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use bytemuck::AnyBitPattern;
+
+use super::{Backend, EcError, Reply};
+use crate::{
+    command::{
+        Cmd, GetChipInfoResponse, GetCmdVersions, GetCmdVersionsResponse, GetCmdVersionsV1,
+        GetFeaturesResponse, GetKeyboardBacklightResponse, GetProtocolInfoResponse,
+        GetUptimeInfoResponse, GetVersionResponse, GetVersionV1Response, Hello, HelloResponse,
+        LedControl, LedControlResponse, PwmGetDuty, PwmGetDutyResponse, PwmSetDuty,
+        SetKeyboardBacklight,
+    },
+    event::Event,
+    memmap::{AmbientLight, Battery, Fans, HostEvents, Id, Region, Switches, Temperatures},
+};
+
+/// A single recorded transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Time since the start of the recording.
+    pub at: Duration,
+    /// How long the transaction took.
+    pub took: Duration,
+    pub op: Op,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    /// A host command, and either the EC's result code and response, or the error that prevented
+    /// the command from reaching the EC.
+    Command {
+        command: u32,
+        version: u32,
+        request: Vec<u8>,
+        reply: Result<(u32, Vec<u8>), String>,
+    },
+    /// A read from the memory-mapped region, and either the bytes read or an error.
+    Memmap {
+        offset: u8,
+        len: usize,
+        reply: Result<Vec<u8>, String>,
+    },
+    /// A change of the event mask, and the error if the backend rejected it.
+    EventMask {
+        mask: u32,
+        reply: Result<(), String>,
+    },
+    /// A read of a pending event, and either the event, `None` if no event was pending, or an
+    /// error.
+    Event {
+        reply: Result<Option<Vec<u8>>, String>,
+    },
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "+{:.6} {:.6} ",
+            self.at.as_secs_f64(),
+            self.took.as_secs_f64()
+        )?;
+        let error = match &self.op {
+            Op::Command {
+                command,
+                version,
+                request,
+                reply,
+            } => {
+                write!(f, "cmd {command:#06x} v{version} req={}", Hex(request))?;
+                match reply {
+                    Ok((result, response)) => {
+                        return write!(f, " result={result} resp={}", Hex(response));
+                    }
+                    Err(e) => e,
+                }
+            }
+            Op::Memmap { offset, len, reply } => {
+                write!(f, "memmap {offset:#04x} len={len}")?;
+                match reply {
+                    Ok(data) => return write!(f, " data={}", Hex(data)),
+                    Err(e) => e,
+                }
+            }
+            Op::EventMask { mask, reply } => {
+                write!(f, "event-mask {mask:#010x}")?;
+                match reply {
+                    Ok(()) => return Ok(()),
+                    Err(e) => e,
+                }
+            }
+            Op::Event { reply } => {
+                write!(f, "event")?;
+                match reply {
+                    Ok(Some(data)) => return write!(f, " data={}", Hex(data)),
+                    Ok(None) => return write!(f, " none"),
+                    Err(e) => e,
+                }
+            }
+        };
+        write!(f, " error: {error}")
+    }
+}
+
+impl Entry {
+    /// Parses a line of a trace file.
+    pub fn parse(line: &str) -> Option<Self> {
+        let (line, error) = match line.split_once(" error: ") {
+            Some((line, error)) => (line, Some(error.to_string())),
+            None => (line, None),
+        };
+        let mut words = line.split_whitespace();
+        let at = parse_secs(words.next()?.strip_prefix('+')?)?;
+        let took = parse_secs(words.next()?)?;
+        let op = match words.next()? {
+            "cmd" => {
+                let command = parse_hex_u32(words.next()?)?;
+                let version = words.next()?.strip_prefix('v')?.parse().ok()?;
+                let request = parse_field(words.next()?, "req")?;
+                let reply = match error {
+                    Some(e) => Err(e),
+                    None => {
+                        let result = words.next()?.strip_prefix("result=")?.parse().ok()?;
+                        Ok((result, parse_field(words.next()?, "resp")?))
+                    }
+                };
+                Op::Command {
+                    command,
+                    version,
+                    request,
+                    reply,
+                }
+            }
+            "memmap" => {
+                let offset = parse_hex_u32(words.next()?)?.try_into().ok()?;
+                let len = words.next()?.strip_prefix("len=")?.parse().ok()?;
+                let reply = match error {
+                    Some(e) => Err(e),
+                    None => Ok(parse_field(words.next()?, "data")?),
+                };
+                Op::Memmap { offset, len, reply }
+            }
+            "event-mask" => Op::EventMask {
+                mask: parse_hex_u32(words.next()?)?,
+                reply: error.map_or(Ok(()), Err),
+            },
+            "event" => Op::Event {
+                reply: match error {
+                    Some(e) => Err(e),
+                    None => match words.next()? {
+                        "none" => Ok(None),
+                        word => Ok(Some(parse_field(word, "data")?)),
+                    },
+                },
+            },
+            _ => return None,
+        };
+        if words.next().is_some() {
+            return None;
+        }
+        Some(Self { at, took, op })
+    }
+}
+
+/// Reads all entries of the trace at `path`.
+pub fn load(path: impl AsRef<Path>) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let entry = Entry::parse(line).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid trace entry on line {}: {}", i + 1, line),
+            )
+        })?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// A trace file being recorded, shared by the [`Recorder`]s writing to it.
+#[derive(Clone)]
+pub struct TraceFile {
+    out: Arc<Mutex<BufWriter<File>>>,
+    start: Instant,
+}
+
+impl TraceFile {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "# keylightd {} EC trace", env!("CARGO_PKG_VERSION"))?;
+        out.flush()?;
+        Ok(Self {
+            out: Arc::new(Mutex::new(out)),
+            start: Instant::now(),
+        })
+    }
+
+    fn record(&self, start: Instant, op: Op) {
+        let entry = Entry {
+            at: start - self.start,
+            took: start.elapsed(),
+            op,
+        };
+        let mut out = self.out.lock().unwrap();
+        if let Err(e) = writeln!(out, "{entry}").and_then(|_| out.flush()) {
+            log::warn!("failed to write EC trace: {}", e);
+        }
+    }
+}
+
+/// [`Backend`] wrapper that writes every transaction to a trace file.
+///
+/// This includes changes of the event mask and every attempt to read an event, but not calls to
+/// [`Backend::event_fd`].
+pub struct Recorder {
+    inner: Box<dyn Backend>,
+    trace: TraceFile,
+}
+
+impl Recorder {
+    /// Records the traffic of `inner` to `trace`.
+    ///
+    /// Several recorders can share a trace, for example when the backend is reopened.
+    pub fn new(inner: Box<dyn Backend>, trace: TraceFile) -> Self {
+        Self { inner, trace }
+    }
+}
+
+impl Backend for Recorder {
+    fn command(
+        &self,
+        command: u32,
+        version: u32,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> io::Result<Reply> {
+        let start = Instant::now();
+        let res = self.inner.command(command, version, request, response);
+        let reply = match &res {
+            Ok(reply) => Ok((reply.result, response[..reply.len].to_vec())),
+            Err(e) => Err(e.to_string()),
+        };
+        self.trace.record(
+            start,
+            Op::Command {
+                command,
+                version,
+                request: request.to_vec(),
+                reply,
+            },
+        );
+        res
+    }
+
+    fn read_memmap(&self, offset: u8, buf: &mut [u8]) -> io::Result<usize> {
+        let start = Instant::now();
+        let res = self.inner.read_memmap(offset, buf);
+        let reply = match &res {
+            Ok(len) => Ok(buf[..*len].to_vec()),
+            Err(e) => Err(e.to_string()),
+        };
+        self.trace.record(
+            start,
+            Op::Memmap {
+                offset,
+                len: buf.len(),
+                reply,
+            },
+        );
+        res
+    }
+
+    fn set_event_mask(&self, mask: u32) -> io::Result<()> {
+        let start = Instant::now();
+        let res = self.inner.set_event_mask(mask);
+        let reply = res.as_ref().map(|_| ()).map_err(|e| e.to_string());
+        self.trace.record(start, Op::EventMask { mask, reply });
+        res
+    }
+
+    fn read_event(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let start = Instant::now();
+        let res = self.inner.read_event(buf);
+        let reply = match &res {
+            Ok(len) => Ok(Some(buf[..*len].to_vec())),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e.to_string()),
+        };
+        self.trace.record(start, Op::Event { reply });
+        res
+    }
+
+    #[cfg(unix)]
+    fn event_fd(&self) -> Option<RawFd> {
+        self.inner.event_fd()
+    }
+}
+
+/// [`Backend`] that answers from a recorded trace.
+///
+/// Transactions have to arrive in the same order and with the same parameters as in the trace,
+/// otherwise an error of kind [`io::ErrorKind::InvalidData`] is returned. Recorded timing is
+/// ignored, so replays are deterministic.
+///
+/// On Unix, [`Backend::event_fd`] is readable whenever the next entry is an event read, so that a
+/// client waiting for events reads them at the same point as the recorded one did.
+pub struct Replay {
+    entries: Mutex<VecDeque<Entry>>,
+    #[cfg(unix)]
+    events: Option<EventSignal>,
+}
+
+/// A socket pair whose receiving end is kept readable while an event is due.
+#[cfg(unix)]
+struct EventSignal {
+    tx: UnixStream,
+    rx: UnixStream,
+    raised: AtomicBool,
+}
+
+#[cfg(unix)]
+impl EventSignal {
+    fn new() -> io::Result<Self> {
+        let (tx, rx) = UnixStream::pair()?;
+        rx.set_nonblocking(true)?;
+        Ok(Self {
+            tx,
+            rx,
+            raised: AtomicBool::new(false),
+        })
+    }
+
+    fn set(&self, raised: bool) -> io::Result<()> {
+        if self.raised.swap(raised, Ordering::Relaxed) == raised {
+            return Ok(());
+        }
+        if raised {
+            (&self.tx).write_all(&[0])
+        } else {
+            io::Read::read_exact(&mut &self.rx, &mut [0])
+        }
+    }
+}
+
+impl Replay {
+    pub fn new(entries: Vec<Entry>) -> Self {
+        let this = Self {
+            entries: Mutex::new(entries.into()),
+            // Without the socket pair, replays still work, but clients never see events arrive.
+            #[cfg(unix)]
+            events: EventSignal::new()
+                .inspect_err(|e| log::warn!("cannot signal replayed EC events: {}", e))
+                .ok(),
+        };
+        this.signal_events(&this.entries.lock().unwrap(), false);
+        this
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(load(path)?))
+    }
+
+    /// Returns the number of entries that haven't been replayed yet.
+    pub fn remaining(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    /// Takes the next entry from the trace, checking that it matches `expected`.
+    fn next(&self, expected: &str, matches: impl FnOnce(&Op) -> bool) -> io::Result<Op> {
+        let mut entries = self.entries.lock().unwrap();
+        let Some(entry) = entries.pop_front() else {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "end of EC trace reached",
+            ));
+        };
+        if !matches(&entry.op) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("replay diverged from EC trace: expected `{entry}`, got `{expected}`"),
+            ));
+        }
+        // A client that drained all pending events waits for the descriptor to become readable
+        // again, which it doesn't notice if it never stopped being readable.
+        let drained = matches!(entry.op, Op::Event { reply: Ok(None) });
+        self.signal_events(&entries, drained);
+        Ok(entry.op)
+    }
+
+    /// Makes the event descriptor readable if the next entry is an event read, first making it
+    /// unreadable if `rearm` is set.
+    fn signal_events(&self, entries: &VecDeque<Entry>, rearm: bool) {
+        #[cfg(unix)]
+        if let Some(events) = &self.events {
+            let due = matches!(
+                entries.front(),
+                Some(Entry {
+                    op: Op::Event { .. },
+                    ..
+                })
+            );
+            let res = match rearm {
+                true => events.set(false).and_then(|_| events.set(due)),
+                false => events.set(due),
+            };
+            if let Err(e) = res {
+                log::warn!("cannot signal replayed EC events: {}", e);
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = (entries, rearm);
+    }
+}
+
+impl Backend for Replay {
+    fn command(
+        &self,
+        command: u32,
+        version: u32,
+        request: &[u8],
+        response: &mut [u8],
+    ) -> io::Result<Reply> {
+        let expected = format!("cmd {command:#06x} v{version} req={}", Hex(request));
+        let op = self.next(&expected, |op| {
+            matches!(op, Op::Command { command: c, version: v, request: r, .. }
+                if *c == command && *v == version && r == request)
+        })?;
+        let Op::Command { reply, .. } = op else {
+            unreachable!()
+        };
+        let (result, data) = reply.map_err(io::Error::other)?;
+        if data.len() > response.len() {
+            return Ok(Reply::error(EcError::ResponseTooBig));
+        }
+        response[..data.len()].copy_from_slice(&data);
+        Ok(Reply {
+            result,
+            len: data.len(),
+        })
+    }
+
+    fn read_memmap(&self, offset: u8, buf: &mut [u8]) -> io::Result<usize> {
+        let expected = format!("memmap {offset:#04x} len={}", buf.len());
+        let op = self.next(&expected, |op| {
+            matches!(op, Op::Memmap { offset: o, len, .. } if *o == offset && *len == buf.len())
+        })?;
+        let Op::Memmap { reply, .. } = op else {
+            unreachable!()
+        };
+        let data = reply.map_err(io::Error::other)?;
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    fn set_event_mask(&self, mask: u32) -> io::Result<()> {
+        let expected = format!("event-mask {mask:#010x}");
+        let op = self.next(
+            &expected,
+            |op| matches!(op, Op::EventMask { mask: m, .. } if *m == mask),
+        )?;
+        let Op::EventMask { reply, .. } = op else {
+            unreachable!()
+        };
+        reply.map_err(io::Error::other)
+    }
+
+    fn read_event(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let op = self.next("event", |op| matches!(op, Op::Event { .. }))?;
+        let Op::Event { reply } = op else {
+            unreachable!()
+        };
+        let data = reply
+            .map_err(io::Error::other)?
+            .ok_or(io::ErrorKind::WouldBlock)?;
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    #[cfg(unix)]
+    fn event_fd(&self) -> Option<RawFd> {
+        self.events.as_ref().map(|events| events.rx.as_raw_fd())
+    }
+}
+
+/// Writes a human-readable description of `entries` to `out`, decoding payloads with the structs
+/// in [`crate::command`] and [`crate::memmap`].
+pub fn dump(entries: &[Entry], out: &mut impl Write) -> io::Result<()> {
+    for entry in entries {
+        write!(out, "+{:.6}  ", entry.at.as_secs_f64())?;
+        let (payloads, outcome) = match &entry.op {
+            Op::Command {
+                command,
+                version,
+                request,
+                reply,
+            } => {
+                match Cmd::from_code(*command) {
+                    Some(cmd) => write!(out, "{cmd:?} ({command:#06x}) v{version}")?,
+                    None => write!(out, "{command:#06x} v{version}")?,
+                }
+                let response = reply.as_ref().map_or(&[][..], |(_, resp)| resp);
+                let (req, resp) = describe_command(*command, *version, request, response);
+                let outcome = match reply {
+                    Ok((result, _)) => EcError::from_code(*result).map(|e| e.to_string()),
+                    Err(e) => Some(format!("error: {e}")),
+                };
+                (vec![("request", req), ("response", resp)], outcome)
+            }
+            Op::Memmap { offset, len, reply } => {
+                write!(out, "memmap {offset:#04x} len={len}")?;
+                let (data, outcome) = match reply {
+                    Ok(data) => (describe_memmap(*offset, data), None),
+                    Err(e) => (String::new(), Some(format!("error: {e}"))),
+                };
+                (vec![("data", data)], outcome)
+            }
+            Op::EventMask { mask, reply } => {
+                write!(out, "event mask {mask:#010x}")?;
+                (
+                    Vec::new(),
+                    reply.as_ref().err().map(|e| format!("error: {e}")),
+                )
+            }
+            Op::Event { reply } => {
+                write!(out, "event")?;
+                let (event, outcome) = match reply {
+                    Ok(Some(data)) => (
+                        Event::parse(data).map_or_else(|| hex(data), |event| format!("{event:?}")),
+                        None,
+                    ),
+                    Ok(None) => (String::new(), Some("none pending".to_string())),
+                    Err(e) => (String::new(), Some(format!("error: {e}"))),
+                };
+                (vec![("event", event)], outcome)
+            }
+        };
+        if let Some(outcome) = outcome {
+            write!(out, "  {outcome}")?;
+        }
+        writeln!(out, "  ({} µs)", entry.took.as_micros())?;
+        for (name, payload) in payloads {
+            if !payload.is_empty() {
+                writeln!(out, "    {:9} {}", format!("{name}:"), payload)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn describe_command(
+    command: u32,
+    version: u32,
+    request: &[u8],
+    response: &[u8],
+) -> (String, String) {
+    let Some(cmd) = Cmd::from_code(command) else {
+        return (hex(request), hex(response));
+    };
+    match (cmd, version) {
+        (Cmd::Hello, _) => (decode::<Hello>(request), decode::<HelloResponse>(response)),
+        (Cmd::GetVersion, 0) => (hex(request), decode::<GetVersionResponse>(response)),
+        (Cmd::GetVersion, _) => (hex(request), decode::<GetVersionV1Response>(response)),
+        (Cmd::GetBuildInfo, _) => (
+            hex(request),
+            format!(
+                "{:?}",
+                String::from_utf8_lossy(response.split(|&b| b == 0).next().unwrap_or_default())
+            ),
+        ),
+        (Cmd::GetChipInfo, _) => (hex(request), decode::<GetChipInfoResponse>(response)),
+        (Cmd::GetCmdVersions, 0) => (
+            decode::<GetCmdVersions>(request),
+            decode::<GetCmdVersionsResponse>(response),
+        ),
+        (Cmd::GetCmdVersions, _) => (
+            decode::<GetCmdVersionsV1>(request),
+            decode::<GetCmdVersionsResponse>(response),
+        ),
+        (Cmd::GetProtocolInfo, _) => (hex(request), decode::<GetProtocolInfoResponse>(response)),
+        (Cmd::GetFeatures, _) => (
+            hex(request),
+            read::<GetFeaturesResponse>(response).map_or_else(
+                || hex(response),
+                |features| format!("{:?}", features.iter().collect::<Vec<_>>()),
+            ),
+        ),
+        (Cmd::GetKeyboardBacklight, _) => (
+            hex(request),
+            decode::<GetKeyboardBacklightResponse>(response),
+        ),
+        (Cmd::SetKeyboardBacklight, _) => (decode::<SetKeyboardBacklight>(request), hex(response)),
+        (Cmd::PwmSetDuty, _) => (decode::<PwmSetDuty>(request), hex(response)),
+        (Cmd::PwmGetDuty, _) => (
+            decode::<PwmGetDuty>(request),
+            decode::<PwmGetDutyResponse>(response),
+        ),
+        (Cmd::LedControl, _) => (
+            decode::<LedControl>(request),
+            decode::<LedControlResponse>(response),
+        ),
+        (Cmd::GetUptimeInfo, _) => (hex(request), decode::<GetUptimeInfoResponse>(response)),
+        _ => (hex(request), hex(response)),
+    }
+}
+
+fn describe_memmap(offset: u8, data: &[u8]) -> String {
+    fn region<R: Region + Debug>(offset: u8, data: &[u8]) -> Option<String> {
+        (offset == R::OFFSET && data.len() == size_of::<R>()).then(|| decode::<R>(data))
+    }
+
+    region::<Temperatures>(offset, data)
+        .or_else(|| region::<Fans>(offset, data))
+        .or_else(|| region::<Id>(offset, data))
+        .or_else(|| region::<Switches>(offset, data))
+        .or_else(|| region::<HostEvents>(offset, data))
+        .or_else(|| region::<Battery>(offset, data))
+        .or_else(|| region::<AmbientLight>(offset, data))
+        .unwrap_or_else(|| hex(data))
+}
+
+fn read<T: AnyBitPattern>(bytes: &[u8]) -> Option<T> {
+    bytes
+        .get(..size_of::<T>())
+        .map(bytemuck::pod_read_unaligned)
+}
+
+/// Decodes `bytes` as a `T`, falling back to hex if there are too few of them.
+fn decode<T: AnyBitPattern + Debug>(bytes: &[u8]) -> String {
+    if bytes.is_empty() {
+        return String::new();
+    }
+    match read::<T>(bytes) {
+        Some(value) => format!("{value:?}"),
+        None => hex(bytes),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    Hex(bytes).to_string()
+}
+
+struct Hex<'a>(&'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in self.0 {
+            write!(f, "{b:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_field(word: &str, name: &str) -> Option<Vec<u8>> {
+    let hex = word.strip_prefix(name)?.strip_prefix('=')?;
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_hex_u32(word: &str) -> Option<u32> {
+    u32::from_str_radix(word.strip_prefix("0x")?, 16).ok()
+}
+
+fn parse_secs(word: &str) -> Option<Duration> {
+    Duration::try_from_secs_f64(word.parse().ok()?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        command::Cmd,
+        ec::sim::SimulatedEc,
+        event::{EventType, HostEvent},
+        memmap::Switches,
+    };
+
+    fn entry(op: Op) -> Entry {
+        Entry {
+            at: Duration::from_micros(2_318_870),
+            took: Duration::from_micros(12),
+            op,
+        }
+    }
+
+    #[test]
+    fn entries_round_trip() {
+        let ops = [
+            Op::Command {
+                command: 0x0023,
+                version: 0,
+                request: vec![0x32],
+                reply: Ok((0, vec![])),
+            },
+            Op::Command {
+                command: 0x0022,
+                version: 1,
+                request: vec![],
+                reply: Err("Connection reset by peer".into()),
+            },
+            Op::Memmap {
+                offset: 0x30,
+                len: 1,
+                reply: Ok(vec![0x01]),
+            },
+            Op::Memmap {
+                offset: 0x30,
+                len: 1,
+                reply: Err("timed out".into()),
+            },
+            Op::EventMask {
+                mask: 0x82,
+                reply: Ok(()),
+            },
+            Op::EventMask {
+                mask: 0x82,
+                reply: Err("operation not supported".into()),
+            },
+            Op::Event {
+                reply: Ok(Some(vec![0x01, 0x01, 0x00, 0x00, 0x00])),
+            },
+            Op::Event { reply: Ok(None) },
+            Op::Event {
+                reply: Err("broken pipe".into()),
+            },
+        ];
+        for op in ops {
+            let entry = entry(op);
+            let line = entry.to_string();
+            assert_eq!(Entry::parse(&line), Some(entry), "{line}");
+        }
+    }
+
+    #[test]
+    fn invalid_entries() {
+        for line in [
+            "",
+            "+0.1 0.1",
+            "0.1 0.1 event none",
+            "+0.1 0.1 event",
+            "+0.1 0.1 event none extra",
+            "+0.1 0.1 event data=0",
+            "+0.1 0.1 event-mask 82",
+            "+0.1 0.1 memmap 0x100 len=1 data=00",
+            "+0.1 0.1 cmd 0x0001 v0 req=00",
+        ] {
+            assert_eq!(Entry::parse(line), None, "{line}");
+        }
+    }
+
+    #[test]
+    fn record_and_replay() {
+        /// Performs a fixed sequence of transactions, returning their outcomes.
+        fn exercise(backend: &dyn Backend, raise: impl FnOnce()) -> Vec<String> {
+            let mut buf = [0; 64];
+            let mut outcomes = vec![
+                format!(
+                    "{:?} {:?}",
+                    backend.command(Cmd::Hello as u32, 0, &[1, 2, 3, 4], &mut buf),
+                    &buf[..4],
+                ),
+                format!("{:?}", backend.read_memmap(Switches::OFFSET, &mut buf[..1])),
+                format!("{:?}", backend.set_event_mask(EventType::HOST_EVENT.mask())),
+                format!("{:?}", backend.read_event(&mut buf).map_err(|e| e.kind())),
+            ];
+            raise();
+            outcomes.push(format!("{:?}", backend.read_event(&mut buf)));
+            outcomes.push(format!("{:?}", &buf[..5]));
+            outcomes
+        }
+
+        let path = std::env::temp_dir().join(format!("keylightd-trace-{}", std::process::id()));
+        let sim = Arc::new(SimulatedEc::default());
+        let recorder = Recorder::new(Box::new(sim.clone()), TraceFile::create(&path).unwrap());
+        let recorded = exercise(&recorder, || sim.raise(HostEvent::LID_CLOSED));
+        let entries = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(entries.len(), 5);
+        assert_eq!(
+            entries[3].op,
+            Op::Event { reply: Ok(None) },
+            "an empty event queue must be recorded as such"
+        );
+
+        let replay = Replay::new(entries);
+        let replayed = exercise(&replay, || {
+            #[cfg(unix)]
+            assert!(
+                replay
+                    .events
+                    .as_ref()
+                    .unwrap()
+                    .raised
+                    .load(Ordering::Relaxed)
+            );
+        });
+        assert_eq!(recorded, replayed);
+        assert_eq!(recorded[3], "Err(WouldBlock)");
+        assert_eq!(
+            replay.read_event(&mut [0; 64]).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn replay_diverges() {
+        let replay = Replay::new(vec![entry(Op::EventMask {
+            mask: 0x82,
+            reply: Ok(()),
+        })]);
+        let err = replay.set_event_mask(0x02).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    /// Clients like keylightd's main loop wait for events with edge-triggered polling, so every
+    /// event read after a drained queue needs a new edge.
+    #[cfg(unix)]
+    #[test]
+    fn events_are_signalled_again_after_draining() {
+        use mio::{Events, Interest, Poll, Token, unix::SourceFd};
+
+        let replay = Replay::new(vec![
+            entry(Op::Event { reply: Ok(None) }),
+            entry(Op::Event {
+                reply: Ok(Some(vec![0x01, 0x01, 0x00, 0x00, 0x00])),
+            }),
+            entry(Op::Event { reply: Ok(None) }),
+            entry(Op::EventMask {
+                mask: 0,
+                reply: Ok(()),
+            }),
+        ]);
+        let mut poll = Poll::new().unwrap();
+        let fd = replay.event_fd().unwrap();
+        poll.registry()
+            .register(&mut SourceFd(&fd), Token(0), Interest::READABLE)
+            .unwrap();
+        let mut events = Events::with_capacity(1);
+        let mut readable = || {
+            poll.poll(&mut events, Some(Duration::ZERO)).unwrap();
+            !events.is_empty()
+        };
+
+        assert!(readable());
+        assert_eq!(
+            replay.read_event(&mut [0; 64]).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        assert!(readable());
+        assert_eq!(replay.read_event(&mut [0; 64]).unwrap(), 5);
+        assert_eq!(
+            replay.read_event(&mut [0; 64]).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        assert!(!readable());
+    }
+}
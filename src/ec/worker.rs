@@ -0,0 +1,63 @@
+//! A dedicated thread that owns the [`Backend`], so that transactions can be abandoned when the EC
+//! hangs.
+
+use std::{
+    io,
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::Duration,
+};
+
+use super::Backend;
+
+type Job = Box<dyn FnOnce(&dyn Backend) + Send>;
+
+pub(crate) struct Worker {
+    jobs: mpsc::Sender<Job>,
+}
+
+/// Why [`Worker::run`] didn't return a result.
+#[derive(Debug)]
+pub(crate) enum RunError {
+    /// The job didn't finish in time. It keeps running (or waiting for earlier jobs to finish) in
+    /// the background.
+    TimedOut,
+    /// The worker thread is gone, because a job panicked.
+    Gone,
+}
+
+impl Worker {
+    pub(crate) fn spawn(backend: Box<dyn Backend>) -> io::Result<Self> {
+        let (jobs, rx) = mpsc::channel::<Job>();
+        thread::Builder::new()
+            .name("ec-worker".into())
+            .spawn(move || {
+                // Exits once the `EmbeddedController` (and with it, the sender) is dropped.
+                for job in rx {
+                    job(&*backend);
+                }
+            })?;
+        Ok(Self { jobs })
+    }
+
+    /// Runs `f` on the worker thread, waiting at most `timeout` for it to finish.
+    ///
+    /// Jobs run one at a time, in the order they were submitted.
+    pub(crate) fn run<T: Send + 'static>(
+        &self,
+        timeout: Duration,
+        f: impl FnOnce(&dyn Backend) -> T + Send + 'static,
+    ) -> Result<T, RunError> {
+        let (tx, rx) = mpsc::sync_channel(1);
+        self.jobs
+            .send(Box::new(move |backend| {
+                // The caller may have stopped waiting, in which case the result is dropped.
+                let _ = tx.send(f(backend));
+            }))
+            .map_err(|_| RunError::Gone)?;
+        rx.recv_timeout(timeout).map_err(|e| match e {
+            RecvTimeoutError::Timeout => RunError::TimedOut,
+            RecvTimeoutError::Disconnected => RunError::Gone,
+        })
+    }
+}
@@ -0,0 +1,187 @@
+//! MKBP events sent by the Embedded Controller.
+//!
+//! Reference: <https://github.com/FrameworkComputer/EmbeddedController/blob/hx20-hx30/include/ec_commands.h>
+//!
+//! (event types begin with `EC_MKBP_EVENT_`, host events with `EC_HOST_EVENT_`)
+
+use std::fmt;
+
+/// Type of an MKBP event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct EventType(u8);
+
+impl EventType {
+    pub const KEY_MATRIX: Self = Self(0);
+    pub const HOST_EVENT: Self = Self(1);
+    pub const SENSOR_FIFO: Self = Self(2);
+    pub const BUTTON: Self = Self(3);
+    pub const SWITCH: Self = Self(4);
+    pub const FINGERPRINT: Self = Self(5);
+    pub const SYSRQ: Self = Self(6);
+    pub const HOST_EVENT64: Self = Self(7);
+    pub const CEC_EVENT: Self = Self(8);
+    pub const CEC_MESSAGE: Self = Self(9);
+
+    /// The top bit of the event type byte signals that more events are pending.
+    const HAS_MORE_EVENTS: u8 = 0x80;
+
+    /// Returns the bit of this event type in an event mask.
+    pub fn mask(self) -> u32 {
+        1 << self.0
+    }
+
+    /// Returns the raw event type byte (`EC_MKBP_EVENT_*`).
+    pub fn code(self) -> u8 {
+        self.0
+    }
+}
+
+/// A host event (`EC_HOST_EVENT_*`).
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct HostEvent(u8);
+
+impl HostEvent {
+    pub const LID_CLOSED: Self = Self(1);
+    pub const LID_OPEN: Self = Self(2);
+    pub const POWER_BUTTON: Self = Self(3);
+    pub const AC_CONNECTED: Self = Self(4);
+    pub const AC_DISCONNECTED: Self = Self(5);
+    pub const BATTERY_LOW: Self = Self(6);
+    pub const BATTERY_CRITICAL: Self = Self(7);
+    pub const BATTERY: Self = Self(8);
+    pub const THERMAL_THRESHOLD: Self = Self(9);
+    pub const DEVICE: Self = Self(10);
+    pub const THERMAL: Self = Self(11);
+    pub const USB_CHARGER: Self = Self(12);
+    pub const KEY_PRESSED: Self = Self(13);
+    pub const INTERFACE_READY: Self = Self(14);
+    pub const KEYBOARD_RECOVERY: Self = Self(15);
+    pub const THERMAL_SHUTDOWN: Self = Self(16);
+    pub const BATTERY_SHUTDOWN: Self = Self(17);
+    pub const THROTTLE_START: Self = Self(18);
+    pub const THROTTLE_STOP: Self = Self(19);
+    pub const HANG_DETECT: Self = Self(20);
+    pub const HANG_REBOOT: Self = Self(21);
+    pub const PD_MCU: Self = Self(22);
+    pub const BATTERY_STATUS: Self = Self(23);
+    pub const PANIC: Self = Self(24);
+    pub const KEYBOARD_FASTBOOT: Self = Self(25);
+    pub const RTC: Self = Self(26);
+    pub const MKBP: Self = Self(27);
+    pub const USB_MUX: Self = Self(28);
+    pub const MODE_CHANGE: Self = Self(29);
+    pub const KEYBOARD_RECOVERY_HW_REINIT: Self = Self(30);
+    pub const WOV: Self = Self(31);
+    pub const INVALID: Self = Self(32);
+
+    /// Returns the bit of this host event in a host event mask.
+    ///
+    /// Host event numbers start at 1, so event N is bit N-1.
+    pub fn mask(self) -> u64 {
+        1 << (self.0 - 1)
+    }
+
+    /// Returns the name of the event, without the `EC_HOST_EVENT_` prefix.
+    pub fn name(self) -> Option<&'static str> {
+        Some(match self {
+            Self::LID_CLOSED => "LID_CLOSED",
+            Self::LID_OPEN => "LID_OPEN",
+            Self::POWER_BUTTON => "POWER_BUTTON",
+            Self::AC_CONNECTED => "AC_CONNECTED",
+            Self::AC_DISCONNECTED => "AC_DISCONNECTED",
+            Self::BATTERY_LOW => "BATTERY_LOW",
+            Self::BATTERY_CRITICAL => "BATTERY_CRITICAL",
+            Self::BATTERY => "BATTERY",
+            Self::THERMAL_THRESHOLD => "THERMAL_THRESHOLD",
+            Self::DEVICE => "DEVICE",
+            Self::THERMAL => "THERMAL",
+            Self::USB_CHARGER => "USB_CHARGER",
+            Self::KEY_PRESSED => "KEY_PRESSED",
+            Self::INTERFACE_READY => "INTERFACE_READY",
+            Self::KEYBOARD_RECOVERY => "KEYBOARD_RECOVERY",
+            Self::THERMAL_SHUTDOWN => "THERMAL_SHUTDOWN",
+            Self::BATTERY_SHUTDOWN => "BATTERY_SHUTDOWN",
+            Self::THROTTLE_START => "THROTTLE_START",
+            Self::THROTTLE_STOP => "THROTTLE_STOP",
+            Self::HANG_DETECT => "HANG_DETECT",
+            Self::HANG_REBOOT => "HANG_REBOOT",
+            Self::PD_MCU => "PD_MCU",
+            Self::BATTERY_STATUS => "BATTERY_STATUS",
+            Self::PANIC => "PANIC",
+            Self::KEYBOARD_FASTBOOT => "KEYBOARD_FASTBOOT",
+            Self::RTC => "RTC",
+            Self::MKBP => "MKBP",
+            Self::USB_MUX => "USB_MUX",
+            Self::MODE_CHANGE => "MODE_CHANGE",
+            Self::KEYBOARD_RECOVERY_HW_REINIT => "KEYBOARD_RECOVERY_HW_REINIT",
+            Self::WOV => "WOV",
+            Self::INVALID => "INVALID",
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Debug for HostEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "HostEvent({})", self.0),
+        }
+    }
+}
+
+/// A set of host events.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct HostEventMask(pub u64);
+
+impl HostEventMask {
+    pub fn contains(self, event: HostEvent) -> bool {
+        self.0 & event.mask() != 0
+    }
+
+    /// Returns an iterator over the events in the set.
+    pub fn iter(self) -> impl Iterator<Item = HostEvent> {
+        (1..=64).map(HostEvent).filter(move |&e| self.contains(e))
+    }
+}
+
+impl fmt::Debug for HostEventMask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+/// An event received from the EC.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// One or more host events were raised.
+    Host(HostEventMask),
+    /// Any other event, with its raw payload.
+    Other {
+        event_type: EventType,
+        data: Vec<u8>,
+    },
+}
+
+impl Event {
+    /// Decodes an event as read from the `cros_ec` character device (the event type byte,
+    /// followed by the event data).
+    pub fn parse(raw: &[u8]) -> Option<Self> {
+        let (&event_type, data) = raw.split_first()?;
+        let event_type = EventType(event_type & !EventType::HAS_MORE_EVENTS);
+        Some(match event_type {
+            EventType::HOST_EVENT => Self::Host(HostEventMask(
+                u32::from_le_bytes(data.get(..4)?.try_into().unwrap()).into(),
+            )),
+            EventType::HOST_EVENT64 => Self::Host(HostEventMask(u64::from_le_bytes(
+                data.get(..8)?.try_into().unwrap(),
+            ))),
+            _ => Self::Other {
+                event_type,
+                data: data.to_vec(),
+            },
+        })
+    }
+}
@@ -0,0 +1,24 @@
+//! Communication with the ChromeOS Embedded Controller (EC) of Framework laptops.
+//!
+//! This is the library the `keylightd` daemon and the `keylightd-ec-sim` development tool are
+//! built on. [`ec::EmbeddedController`] is the entry point: it sends the typed commands from
+//! [`command`] (or raw ones) to the EC, reads its memory map ([`memmap`]) and receives its
+//! events ([`event`]).
+//!
+//! ```no_run
+//! use keylightd::{command::SetKeyboardBacklight, ec::EmbeddedController};
+//!
+//! let ec = EmbeddedController::open()?;
+//! ec.command(SetKeyboardBacklight { percent: 50 })?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! Only the commands keylightd needs are implemented. Others can be added by implementing
+//! [`command::Command`], or sent with [`ec::EmbeddedController::command_raw`].
+
+pub mod command;
+pub mod ec;
+pub mod event;
+pub mod memmap;
+
+mod util;
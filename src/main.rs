@@ -1,13 +1,25 @@
 use argh::FromArgs;
-use command::{GetKeyboardBacklight, SetKeyboardBacklight};
-use ec::EmbeddedController;
+use keylightd::{
+    command::{
+        Cmd, Feature, GetBuildInfo, GetChipInfo, GetKeyboardBacklight, GetUptimeInfo, GetVersion,
+        LedBrightnesses, LedControl, LedFlags, LedId, PWM_MAX_DUTY, PwmGetDuty, PwmSetDuty,
+        ResetFlags, SetKeyboardBacklight,
+    },
+    ec::{
+        self, Backend, Batch, EcError, EmbeddedController, Recorder, Replay, RetryPolicy,
+        sim::{SimState, SimulatedEc},
+        trace::{self, TraceFile},
+    },
+    event::{Event, EventType, HostEvent},
+    memmap::Switches,
+};
 use mio::{Events, Interest, Poll, Token};
-use std::{io, thread, time::Duration};
-
-use crate::command::{LedBrightnesses, LedControl, LedFlags, LedId};
-
-mod command;
-mod ec;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
 
 /// keylightd - automatic keyboard backlight daemon for Framework laptops
 #[derive(Debug, FromArgs)]
@@ -19,65 +31,382 @@ struct Args {
     /// also control the power LED in the fingerprint module
     #[argh(switch)]
     power: bool,
+
+    /// at startup, wait up to this many seconds for the EC device node and the input devices to
+    /// appear [default=0]
+    #[argh(option, default = "0")]
+    wait: u32,
+
+    /// the EC device node to use [default=/dev/cros_ec]
+    #[argh(option, default = "PathBuf::from(ec::DEVICE_PATH)")]
+    ec_device: PathBuf,
+
+    /// talk to the EC over its LPC I/O ports instead of the EC device, for kernels without the
+    /// cros_ec driver (must not be used while the driver is loaded)
+    #[argh(switch)]
+    ec_lpc: bool,
+
+    /// list the EC devices found in sysfs and exit
+    #[argh(switch)]
+    ec_list: bool,
+
+    /// run against a simulated embedded controller instead of the EC device
+    #[argh(switch)]
+    simulate: bool,
+
+    /// connect to a keylightd-ec-sim listening on this Unix socket instead of the EC device
+    #[argh(option)]
+    ec_socket: Option<PathBuf>,
+
+    /// how many times to try EC commands that fail because the EC is busy [default=5]
+    #[argh(option, default = "5")]
+    ec_attempts: u32,
+
+    /// give up on EC commands that take longer than this many milliseconds [default=2000]
+    #[argh(option, default = "2000")]
+    ec_timeout: u64,
+
+    /// record all EC traffic to this trace file
+    #[argh(option)]
+    ec_record: Option<PathBuf>,
+
+    /// answer EC commands from this trace file instead of talking to an EC
+    #[argh(option)]
+    ec_replay: Option<PathBuf>,
+
+    /// print a decoded EC trace file and exit
+    #[argh(option)]
+    ec_dump: Option<PathBuf>,
+
+    /// the Unix socket on which the daemon answers `ec stats` queries
+    /// [default=/run/keylightd.sock]
+    #[argh(option, default = "PathBuf::from(STATUS_SOCKET_PATH)")]
+    status_socket: PathBuf,
+
+    #[argh(subcommand)]
+    command: Option<Command>,
 }
 
-fn fade_to(ec: &EmbeddedController, power: bool, target: u8) -> io::Result<()> {
-    let resp = ec.command(GetKeyboardBacklight)?;
-    let mut cur = if resp.enabled != 0 { resp.percent } else { 0 };
-    while cur != target {
-        if cur > target {
-            cur -= 1;
-        } else {
-            cur += 1;
-        }
+#[derive(Debug, FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    Ec(EcArgs),
+}
+
+/// query the embedded controller or the running daemon instead of running the daemon
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "ec")]
+struct EcArgs {
+    #[argh(subcommand)]
+    command: EcCommand,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand)]
+enum EcCommand {
+    Info(EcInfo),
+    Stats(EcStats),
+}
+
+/// print the EC's firmware version, build and chip information
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "info")]
+struct EcInfo {}
+
+/// print the running daemon's EC command statistics
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "stats")]
+struct EcStats {}
 
-        if power {
+/// Time between two steps of a fade.
+const FADE_INTERVAL: Duration = Duration::from_millis(4);
+
+fn fade_to(ec: &EmbeddedController, power: bool, pwm: bool, target: u8) -> Result<(), ec::Error> {
+    let from = if pwm {
+        ec.command(PwmGetDuty::keyboard_backlight())?.duty
+    } else {
+        let resp = ec.command(GetKeyboardBacklight)?;
+        percent_to_duty(if resp.enabled != 0 { resp.percent } else { 0 })
+    };
+    let to = percent_to_duty(target);
+
+    let mut batch = Batch::new();
+    let mut prev = from;
+    let mut slot = Duration::ZERO;
+    for duty in fade_steps(from, to, pwm) {
+        let deadline = slot + FADE_INTERVAL;
+        if power && (prev == 0) != (duty == 0) {
             // The power LED cannot be faded from software (although the beta BIOS apparently
             // has a switch for dimming it, so maybe it'll work with the next BIOS update).
             // So instead, we treat 0 as off and set it back to auto for any non-zero value.
-            if cur == 0 {
-                ec.command(LedControl {
-                    led_id: LedId::POWER,
-                    flags: LedFlags::NONE,
-                    brightness: LedBrightnesses::default(),
-                })?;
-            } else if cur == 1 {
-                ec.command(LedControl {
-                    led_id: LedId::POWER,
-                    flags: LedFlags::AUTO,
-                    brightness: LedBrightnesses::default(),
-                })?;
-            }
+            batch.push(power_led(duty != 0), slot, deadline);
+        }
+        if pwm {
+            batch.push(PwmSetDuty::keyboard_backlight(duty), slot, deadline);
+        } else {
+            let percent = duty_to_percent(duty);
+            batch.push(SetKeyboardBacklight { percent }, slot, deadline);
+        }
+        prev = duty;
+        slot = deadline;
+    }
+
+    let report = ec.run_batch(batch)?.into_result()?;
+    for step in report.steps.iter().filter(|step| step.missed_deadline()) {
+        log::trace!(
+            "fade step {:#06x} missed its deadline by {:?}",
+            step.command,
+            step.lateness()
+        );
+    }
+    log::debug!(
+        "faded from {:.1}% to {}% in {:?}, {} of {} steps late (by up to {:?})",
+        f64::from(from) * 100.0 / f64::from(PWM_MAX_DUTY),
+        target,
+        report.elapsed,
+        report.missed(),
+        report.steps.len(),
+        report.max_lateness(),
+    );
+    Ok(())
+}
+
+/// Returns the PWM duty cycles to step through when fading from `from` to `to`, one per
+/// [`FADE_INTERVAL`].
+///
+/// Without `pwm`, the backlight can only be set in whole percent, so the fade takes one step
+/// per percent. With it, the fade takes just as many steps, but they're spaced evenly in
+/// perceived brightness rather than in duty cycle. This smooths out the low end, where a single
+/// percent makes a visible difference.
+fn fade_steps(from: u16, to: u16, pwm: bool) -> Vec<u16> {
+    let (from_percent, to_percent) = (duty_to_percent(from), duty_to_percent(to));
+    if !pwm {
+        let steps: Vec<u8> = if from_percent < to_percent {
+            (from_percent + 1..=to_percent).collect()
+        } else {
+            (to_percent..from_percent).rev().collect()
+        };
+        return steps.into_iter().map(percent_to_duty).collect();
+    }
+    if from == to {
+        return Vec::new();
+    }
+
+    // Perceived brightness is roughly the square root of the duty cycle.
+    let max = f64::from(PWM_MAX_DUTY);
+    let (start, end) = ((f64::from(from) / max).sqrt(), (f64::from(to) / max).sqrt());
+    let steps = from_percent.abs_diff(to_percent).max(1);
+    (1..=steps)
+        .map(|i| {
+            let level = start + (end - start) * f64::from(i) / f64::from(steps);
+            (level * level * max).round() as u16
+        })
+        .collect()
+}
+
+fn percent_to_duty(percent: u8) -> u16 {
+    (u32::from(percent.min(100)) * u32::from(PWM_MAX_DUTY) / 100) as u16
+}
+
+/// Converts a duty cycle to the nearest percentage.
+fn duty_to_percent(duty: u16) -> u8 {
+    ((u32::from(duty) * 100 + u32::from(PWM_MAX_DUTY) / 2) / u32::from(PWM_MAX_DUTY)) as u8
+}
+
+/// Checks whether the keyboard backlight can be set with `EC_CMD_PWM_SET_DUTY`.
+fn supports_pwm_duty(ec: &EmbeddedController) -> Result<bool, ec::Error> {
+    match ec.command(PwmGetDuty::keyboard_backlight()) {
+        Ok(_) => Ok(true),
+        Err(
+            e @ (ec::Error::Ec(EcError::InvalidCommand | EcError::InvalidParam)
+            | ec::Error::UnsupportedVersion { .. }),
+        ) => {
+            log::debug!("cannot set keyboard backlight PWM directly: {}", e);
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn log_firmware_version(ec: &EmbeddedController) {
+    match ec.command_versioned(&GetVersion) {
+        Ok(version) => log::info!(
+            "EC firmware: {} ({} image)",
+            version.current_version(),
+            version.current_image()
+        ),
+        Err(e) => log::warn!("cannot read EC firmware version: {}", e),
+    }
+}
+
+/// Prints everything there is to know about the EC's firmware, in the same format as the
+/// `cros_ec` driver's `version` attribute in sysfs.
+fn print_ec_info(ec: &EmbeddedController) -> anyhow::Result<()> {
+    let version = ec.command_versioned(&GetVersion)?;
+    println!("RO version:    {}", version.ro_version());
+    println!("RW version:    {}", version.rw_version());
+    println!("Firmware copy: {}", version.current_image());
+    match ec.command_versioned(&GetBuildInfo) {
+        Ok(info) => println!("Build info:    {}", info),
+        Err(e) => log::warn!("cannot read EC build info: {}", e),
+    }
+    match ec.command(GetChipInfo) {
+        Ok(chip) => {
+            println!("Chip vendor:   {}", chip.vendor());
+            println!("Chip name:     {}", chip.name());
+            println!("Chip revision: {}", chip.revision());
+        }
+        Err(e) => log::warn!("cannot read EC chip info: {}", e),
+    }
+    let protocol = ec.protocol_info();
+    println!(
+        "Protocol:      versions {:#b}, max request {} bytes, max response {} bytes",
+        protocol.versions, protocol.max_request, protocol.max_response
+    );
+    if let Some(features) = ec.features() {
+        println!("Features:      {:?}", features.iter().collect::<Vec<_>>());
+    }
+    Ok(())
+}
+
+/// Describes the EC's per-command latency statistics, one line per command.
+fn describe_ec_stats(ec: &EmbeddedController) -> Vec<String> {
+    ec.stats()
+        .into_iter()
+        .map(|(command, stats)| match Cmd::from_code(command) {
+            Some(cmd) => format!("EC command {cmd:?}: {stats}"),
+            None => format!("EC command {command:#06x}: {stats}"),
+        })
+        .collect()
+}
+
+/// Logs the EC's per-command latency statistics.
+fn log_ec_stats(ec: &EmbeddedController, level: log::Level) {
+    for line in describe_ec_stats(ec) {
+        log::log!(level, "{}", line);
+    }
+}
+
+fn power_led(on: bool) -> LedControl {
+    LedControl {
+        led_id: LedId::POWER,
+        flags: if on { LedFlags::AUTO } else { LedFlags::NONE },
+        brightness: LedBrightnesses::default(),
+    }
+}
+
+fn set_power_led(ec: &EmbeddedController, on: bool) -> Result<(), ec::Error> {
+    ec.command(power_led(on))?;
+    Ok(())
+}
+
+/// Names of the Framework's builtin touchpad and keyboard.
+const INPUT_DEVICES: &[&str] = &[
+    "PIXA3854:00 093A:0274 Touchpad",
+    "AT Translated Set 2 keyboard",
+];
+
+/// Waits for up to `timeout` until the EC device node (if any) and all [`INPUT_DEVICES`] exist.
+///
+/// Early during boot, the kernel modules providing them may not have been loaded yet. Gives up
+/// with a warning once the timeout expires, and lets the caller deal with whatever is missing.
+#[cfg(unix)]
+fn wait_for_devices(ec_device: Option<&Path>, timeout: Duration) -> io::Result<()> {
+    use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+    use std::os::fd::{AsFd, AsRawFd};
+
+    let deadline = Instant::now() + timeout;
+    let inotify = Inotify::init(InitFlags::IN_CLOEXEC | InitFlags::IN_NONBLOCK)?;
+    let mut poller = Poll::new()?;
+    poller.registry().register(
+        &mut mio::unix::SourceFd(&inotify.as_fd().as_raw_fd()),
+        Token(0),
+        Interest::READABLE,
+    )?;
+    let mut events = Events::with_capacity(1);
+
+    let mut waiting_for = Vec::new();
+    loop {
+        for dir in ["/dev", "/dev/input"] {
+            // udev creates device nodes first and fixes up their permissions afterwards, so
+            // attribute changes can make a device usable too. `/dev/input` may not exist yet, in
+            // which case we'll try again when something appears in `/dev`.
+            let _ = inotify.add_watch(dir, AddWatchFlags::IN_CREATE | AddWatchFlags::IN_ATTRIB);
         }
 
-        ec.command(SetKeyboardBacklight { percent: cur })?;
+        let missing = missing_devices(ec_device);
+        if missing.is_empty() {
+            if !waiting_for.is_empty() {
+                log::info!("all devices are present");
+            }
+            return Ok(());
+        }
+        if missing != waiting_for {
+            log::info!("waiting for {}", missing.join(", "));
+            waiting_for = missing;
+        }
 
-        thread::sleep(Duration::from_millis(3));
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            log::warn!(
+                "gave up waiting for {} after {:?}",
+                waiting_for.join(", "),
+                timeout
+            );
+            return Ok(());
+        }
+        poller.poll(&mut events, Some(left))?;
+        match inotify.read_events() {
+            Ok(_) | Err(nix::errno::Errno::EAGAIN) => {}
+            Err(e) => return Err(e.into()),
+        }
     }
+}
+
+#[cfg(windows)]
+fn wait_for_devices(ec_device: Option<&Path>, timeout: Duration) -> io::Result<()> {
     Ok(())
 }
 
+/// Returns the devices [`wait_for_devices`] is still waiting for.
+#[cfg(unix)]
+fn missing_devices(ec_device: Option<&Path>) -> Vec<String> {
+    let mut missing = Vec::new();
+    if let Some(path) = ec_device.filter(|path| !path.exists()) {
+        missing.push(path.display().to_string());
+    }
+    let present = evdev::enumerate()
+        .filter_map(|(_, device)| device.name().map(str::to_string))
+        .collect::<Vec<_>>();
+    for name in INPUT_DEVICES {
+        if !present.iter().any(|p| p == name) {
+            missing.push(format!("input device \"{name}\""));
+        }
+    }
+    missing
+}
+
 #[cfg(unix)]
 fn register_devices(poller: &Poll, devices: &mut Vec<evdev::Device>) -> io::Result<()> {
     for (_, device) in evdev::enumerate() {
         // Filter devices so that only the Framework's builtin touchpad and keyboard are listened
         // to. Since we don't support hotplug, listening on USB devices wouldn't work reliably.
-        match device.name() {
-            Some("PIXA3854:00 093A:0274 Touchpad" | "AT Translated Set 2 keyboard") => {
-                log::info!(
-                    "Got device - {} - {:?}",
-                    device.name().unwrap(),
-                    device.input_id()
-                );
-
-                poller.registry().register(
-                    &mut mio::unix::SourceFd(&std::os::fd::AsRawFd::as_raw_fd(&device)),
-                    Token(device.input_id().product() as usize),
-                    Interest::READABLE,
-                )?;
-                devices.push(device);
-            }
-            _ => {}
+        if device
+            .name()
+            .is_some_and(|name| INPUT_DEVICES.contains(&name))
+        {
+            log::info!(
+                "Got device - {} - {:?}",
+                device.name().unwrap(),
+                device.input_id()
+            );
+
+            poller.registry().register(
+                &mut mio::unix::SourceFd(&std::os::fd::AsRawFd::as_raw_fd(&device)),
+                Token(device.input_id().product() as usize),
+                Interest::READABLE,
+            )?;
+            devices.push(device);
         }
     }
     Ok(())
@@ -88,6 +417,587 @@ fn register_devices(poller: &Poll, devices: &mut Vec<u8>) -> io::Result<()> {
     Ok(())
 }
 
+/// Turns EC timeouts into `None`, so that a hung EC doesn't take the daemon down with it.
+///
+/// The timed-out operation is simply retried the next time around the main loop.
+fn allow_timeout<T>(res: Result<T, ec::Error>) -> Result<Option<T>, ec::Error> {
+    match res {
+        Ok(value) => Ok(Some(value)),
+        Err(e @ ec::Error::Timeout { .. }) => {
+            log::warn!("{}", e);
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Token for events from the EC, chosen so that it can't clash with an input device's product ID.
+const EC_EVENTS: Token = Token(usize::MAX);
+
+#[cfg(unix)]
+fn subscribe_ec_events(poller: &Poll, ec: &EmbeddedController) -> io::Result<()> {
+    let mask = EventType::HOST_EVENT.mask() | EventType::HOST_EVENT64.mask();
+    if let Err(e) = ec.subscribe(mask) {
+        log::info!("EC events are not available: {}", e);
+        return Ok(());
+    }
+    if let Some(fd) = ec.event_fd() {
+        poller
+            .registry()
+            .register(&mut mio::unix::SourceFd(&fd), EC_EVENTS, Interest::READABLE)?;
+        log::debug!("subscribed to EC events");
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn subscribe_ec_events(poller: &Poll, ec: &EmbeddedController) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn unsubscribe_ec_events(poller: &Poll, ec: &EmbeddedController) {
+    if let Some(fd) = ec.event_fd() {
+        // The descriptor may already be closed, in which case it's no longer registered anyway.
+        let _ = poller.registry().deregister(&mut mio::unix::SourceFd(&fd));
+    }
+}
+
+#[cfg(windows)]
+fn unsubscribe_ec_events(poller: &Poll, ec: &EmbeddedController) {}
+
+/// Token for SIGUSR1, which asks the daemon to log its EC command statistics.
+const STATS_SIGNAL: Token = Token(usize::MAX - 1);
+
+#[cfg(unix)]
+type StatsSignal = nix::sys::signalfd::SignalFd;
+
+#[cfg(windows)]
+type StatsSignal = ();
+
+/// Blocks SIGUSR1 and registers a descriptor that receives it instead.
+///
+/// Has to be called before any threads are spawned, since they inherit the signal mask and would
+/// otherwise be killed by the signal.
+#[cfg(unix)]
+fn register_stats_signal(poller: &Poll) -> io::Result<StatsSignal> {
+    use nix::sys::{
+        signal::{SigSet, Signal},
+        signalfd::{SfdFlags, SignalFd},
+    };
+
+    let mut mask = SigSet::empty();
+    mask.add(Signal::SIGUSR1);
+    mask.thread_block()?;
+    let signal = SignalFd::with_flags(&mask, SfdFlags::SFD_NONBLOCK | SfdFlags::SFD_CLOEXEC)?;
+    poller.registry().register(
+        &mut mio::unix::SourceFd(&std::os::fd::AsRawFd::as_raw_fd(&signal)),
+        STATS_SIGNAL,
+        Interest::READABLE,
+    )?;
+    Ok(signal)
+}
+
+#[cfg(windows)]
+fn register_stats_signal(poller: &Poll) -> io::Result<StatsSignal> {
+    Ok(())
+}
+
+/// Consumes all pending SIGUSR1 signals.
+#[cfg(unix)]
+fn drain_stats_signal(signal: &StatsSignal) {
+    while let Ok(Some(_)) = signal.read_signal() {}
+}
+
+#[cfg(windows)]
+fn drain_stats_signal(signal: &StatsSignal) {}
+
+/// Where the daemon answers `ec stats` queries by default.
+const STATUS_SOCKET_PATH: &str = "/run/keylightd.sock";
+
+/// Token for connections to the status socket.
+const STATUS_SOCKET: Token = Token(usize::MAX - 2);
+
+#[cfg(unix)]
+type StatusSocket = std::os::unix::net::UnixListener;
+
+#[cfg(windows)]
+type StatusSocket = ();
+
+/// Creates the socket at `path` that `keylightd ec stats` connects to, replacing a stale one left
+/// behind by a previous instance. A socket another instance is still listening on is left alone.
+///
+/// The statistics aren't essential, so failing to create the socket (eg. when running without
+/// access to `/run`) only disables the query.
+#[cfg(unix)]
+fn register_status_socket(poller: &Poll, path: &Path) -> Option<StatusSocket> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    let res = (|| -> io::Result<StatusSocket> {
+        if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+            // Only a socket nobody listens on anymore is stale.
+            match std::os::unix::net::UnixStream::connect(path) {
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        "another keylightd is already answering status queries there",
+                    ));
+                }
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                    std::fs::remove_file(path)?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        let listener = StatusSocket::bind(path)?;
+        listener.set_nonblocking(true)?;
+        // Anyone may look at the statistics, which requires write access to the socket.
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o666))?;
+        poller.registry().register(
+            &mut mio::unix::SourceFd(&std::os::fd::AsRawFd::as_raw_fd(&listener)),
+            STATUS_SOCKET,
+            Interest::READABLE,
+        )?;
+        Ok(listener)
+    })();
+    match res {
+        Ok(listener) => {
+            log::debug!("answering status queries on {}", path.display());
+            Some(listener)
+        }
+        Err(e) => {
+            log::warn!("cannot create status socket {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+#[cfg(windows)]
+fn register_status_socket(poller: &Poll, path: &Path) -> Option<StatusSocket> {
+    None
+}
+
+/// Sends the EC command statistics to every client waiting on the status socket.
+#[cfg(unix)]
+fn serve_status(socket: &StatusSocket, ec: &EmbeddedController) {
+    use std::io::Write;
+
+    let mut report = String::from("EC command statistics since startup:\n");
+    for line in describe_ec_stats(ec) {
+        report.push_str(&line);
+        report.push('\n');
+    }
+    loop {
+        let mut client = match socket.accept() {
+            Ok((client, _)) => client,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => {
+                log::warn!("cannot accept status query: {}", e);
+                break;
+            }
+        };
+        // A client that doesn't read its answer must not stall the daemon.
+        let res = client
+            .set_nonblocking(true)
+            .and_then(|()| client.write_all(report.as_bytes()));
+        if let Err(e) = res {
+            log::debug!("cannot answer status query: {}", e);
+        }
+    }
+}
+
+#[cfg(windows)]
+fn serve_status(socket: &StatusSocket, ec: &EmbeddedController) {}
+
+/// Asks the daemon listening on `path` for its EC command statistics.
+#[cfg(unix)]
+fn query_status(path: &Path) -> io::Result<String> {
+    use std::io::Read;
+
+    let mut stream = std::os::unix::net::UnixStream::connect(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("cannot connect to keylightd at {}: {e}", path.display()),
+        )
+    })?;
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer)?;
+    Ok(answer)
+}
+
+#[cfg(windows)]
+fn query_status(path: &Path) -> io::Result<String> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(unix)]
+fn connect_socket(path: &std::path::Path) -> io::Result<Box<dyn Backend>> {
+    Ok(Box::new(keylightd::ec::Socket::connect(path)?))
+}
+
+#[cfg(windows)]
+fn connect_socket(path: &std::path::Path) -> io::Result<Box<dyn Backend>> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(unix)]
+fn open_lpc() -> io::Result<Box<dyn Backend>> {
+    Ok(Box::new(keylightd::ec::Lpc::open()?))
+}
+
+#[cfg(windows)]
+fn open_lpc() -> io::Result<Box<dyn Backend>> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Connects to the EC backend selected on the command line.
+fn connect(args: &Args) -> anyhow::Result<EmbeddedController> {
+    let mut ec = open_ec(args)?;
+    ec.set_retry_policy(RetryPolicy {
+        attempts: args.ec_attempts.max(1),
+        ..RetryPolicy::default()
+    });
+    ec.set_timeout(Duration::from_millis(args.ec_timeout));
+    Ok(ec)
+}
+
+fn open_ec(args: &Args) -> anyhow::Result<EmbeddedController> {
+    let trace = args.ec_record.as_ref().map(TraceFile::create).transpose()?;
+    let record = move |backend: Box<dyn Backend>| -> Box<dyn Backend> {
+        match &trace {
+            Some(trace) => Box::new(Recorder::new(backend, trace.clone())),
+            None => backend,
+        }
+    };
+
+    if let Some(path) = &args.ec_replay {
+        // A trace can only be replayed once, so this one cannot be reopened.
+        let backend = record(Box::new(Replay::open(path)?));
+        return Ok(EmbeddedController::with_backend(backend)?);
+    }
+
+    let simulate = args.simulate;
+    let lpc = args.ec_lpc;
+    let socket = args.ec_socket.clone();
+    let device = args.ec_device.clone();
+    Ok(EmbeddedController::with_opener(move || {
+        let backend: Box<dyn Backend> = if simulate {
+            Box::new(SimulatedEc::new(SimState {
+                percent: 50,
+                enabled: true,
+                ..SimState::default()
+            }))
+        } else if let Some(path) = &socket {
+            connect_socket(path)?
+        } else if lpc {
+            open_lpc()?
+        } else {
+            ec::open_backend(&device)?
+        };
+        Ok(record(backend))
+    })?)
+}
+
+/// The longest we wait between attempts to reconnect to the EC.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Reopens the EC after it failed or stopped responding, and restores the backlight state.
+///
+/// This covers the EC being reset (eg. by a firmware update), and the driver going away across
+/// suspend and resume. Retries for as long as the EC is unreachable, but gives up if the backend
+/// cannot be reopened at all, or if the EC rejects restoring the backlight state.
+fn recover(
+    ec: &mut EmbeddedController,
+    poller: &Poll,
+    power: bool,
+    backlight: &mut Backlight,
+) -> anyhow::Result<()> {
+    if !ec.can_reopen() {
+        anyhow::bail!("EC backend cannot be reopened");
+    }
+    unsubscribe_ec_events(poller, ec);
+
+    let mut delay = Duration::from_millis(100);
+    loop {
+        match reconnect(ec, power, backlight) {
+            Ok(()) => break,
+            Err(e) if is_unreachable(&e) => {
+                log::warn!("failed to reconnect to EC: {}, retrying in {:?}", e, delay);
+                thread::sleep(delay);
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    log::info!("reconnected to EC");
+    // The EC may have been reset to install a firmware update.
+    log_firmware_version(ec);
+
+    subscribe_ec_events(poller, ec)?;
+    Ok(())
+}
+
+/// Returns whether `e` means that the EC can't be reached right now, as opposed to the EC
+/// rejecting what we asked of it.
+///
+/// Only the former is worth waiting out: the device node comes back after resume, and the EC
+/// answers again once it's done rebooting. Doing the same thing again won't change the EC's mind
+/// about a command it rejected, though.
+fn is_unreachable(e: &ec::Error) -> bool {
+    match e {
+        ec::Error::Io(_) | ec::Error::Timeout { .. } => true,
+        ec::Error::Ec(e) => e.is_transient(),
+        ec::Error::ShortResponse { .. }
+        | ec::Error::RequestTooLarge { .. }
+        | ec::Error::ResponseTooLarge { .. }
+        | ec::Error::UnsupportedVersion { .. } => false,
+    }
+}
+
+fn reconnect(
+    ec: &mut EmbeddedController,
+    power: bool,
+    backlight: &mut Backlight,
+) -> Result<(), ec::Error> {
+    ec.reopen()?;
+    // A firmware update may have added or removed support for setting the PWM duty cycle.
+    backlight.pwm = supports_pwm_duty(ec)?;
+    reapply(ec, power, backlight)
+}
+
+/// Sets the backlight (and power LED) to what the daemon last set them to.
+fn reapply(ec: &EmbeddedController, power: bool, backlight: &Backlight) -> Result<(), ec::Error> {
+    let percent = if backlight.active {
+        backlight.max_brightness
+    } else {
+        0
+    };
+    if power {
+        set_power_led(ec, percent != 0)?;
+    }
+    ec.command(SetKeyboardBacklight { percent })?;
+    Ok(())
+}
+
+/// The state of the keyboard backlight, as far as the daemon is concerned.
+struct Backlight {
+    /// Whether the backlight has been turned on because of recent activity.
+    active: bool,
+    /// The brightness to fade in to.
+    max_brightness: u8,
+    /// Whether the EC supports `EC_CMD_PWM_SET_DUTY`, which allows for smoother fades.
+    pwm: bool,
+    /// When the user last touched the keyboard or touchpad (or opened the lid).
+    last_input: Instant,
+}
+
+impl Backlight {
+    /// Returns how long to wait for input before the backlight should be turned off, or `None`
+    /// if it's already off.
+    fn time_left(&self, idle_timeout: Duration) -> Option<Duration> {
+        self.active
+            .then(|| (self.last_input + idle_timeout).saturating_duration_since(Instant::now()))
+    }
+}
+
+/// What woke up the main loop.
+#[derive(Debug, Clone, Copy, Default)]
+struct Wakeup {
+    /// An input device has pending events.
+    input: bool,
+    /// The EC has pending events.
+    ec_events: bool,
+    /// The daemon received SIGUSR1.
+    stats: bool,
+    /// Someone connected to the status socket.
+    status: bool,
+}
+
+impl Wakeup {
+    fn from_events(events: &Events) -> Self {
+        let mut wakeup = Self::default();
+        for event in events {
+            if event.token() == EC_EVENTS {
+                wakeup.ec_events = true;
+            } else if event.token() == STATS_SIGNAL {
+                wakeup.stats = true;
+            } else if event.token() == STATUS_SOCKET {
+                wakeup.status = true;
+            } else {
+                wakeup.input = true;
+            }
+        }
+        wakeup
+    }
+}
+
+/// Handles one wakeup of the main loop: fades the backlight in on activity, or out once there
+/// was no activity for `idle_timeout`.
+fn handle_events(
+    ec: &EmbeddedController,
+    wakeup: Wakeup,
+    power: bool,
+    idle_timeout: Duration,
+    backlight: &mut Backlight,
+) -> Result<(), ec::Error> {
+    let mut input = wakeup.input;
+    let mut lid_closed = false;
+    if wakeup.ec_events {
+        // The event descriptor is edge-triggered, so it has to be drained completely, or we won't
+        // hear about any further events.
+        loop {
+            let event = match ec.read_event() {
+                Ok(Some(event)) => event,
+                Ok(None) => break,
+                Err(e @ ec::Error::Timeout { .. }) if ec.is_healthy() => {
+                    log::warn!("{}", e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            log::debug!("EC event: {:?}", event);
+            if let Event::Host(host) = event {
+                // Opening the lid counts as activity, closing it turns the backlight off
+                // right away. Other events (eg. from the charger) don't count as activity.
+                lid_closed |= host.contains(HostEvent::LID_CLOSED);
+                input |= host.contains(HostEvent::LID_OPEN);
+            }
+        }
+    }
+    if input {
+        backlight.last_input = Instant::now();
+    }
+
+    let idle = backlight.time_left(idle_timeout) == Some(Duration::ZERO);
+    if idle || lid_closed {
+        if backlight.active {
+            // Remember the brightness the user picked (eg. with Fn+Space) so that fading back
+            // in restores it.
+            let Some(resp) = allow_timeout(ec.command(GetKeyboardBacklight))? else {
+                return Ok(());
+            };
+            backlight.max_brightness = resp.percent;
+            if allow_timeout(fade_to(ec, power, backlight.pwm, 0))?.is_some() {
+                backlight.active = false;
+                log_ec_stats(ec, log::Level::Debug);
+            }
+        }
+    } else if input {
+        if !backlight.active
+            && allow_timeout(fade_to(ec, power, backlight.pwm, backlight.max_brightness))?.is_some()
+        {
+            backlight.active = true;
+        }
+
+        // Limit the rate of fade-in updates.
+        thread::sleep(Duration::from_millis(500));
+    }
+    Ok(())
+}
+
+/// Notices when the EC reboots behind our back (eg. after a crash or a watchdog reset), by
+/// watching its uptime go backwards.
+///
+/// A reset EC comes back with the backlight at its default brightness and the LEDs under automatic
+/// control, but doesn't raise an event or fail any commands, so there's nothing else to go by.
+struct ResetDetector {
+    /// The EC's uptime in milliseconds, and when we read it.
+    last: Option<(u32, Instant)>,
+    next_check: Instant,
+    /// Cleared if the EC doesn't implement `EC_CMD_GET_UPTIME_INFO`.
+    supported: bool,
+}
+
+impl ResetDetector {
+    /// How often to query the EC's uptime.
+    const INTERVAL: Duration = Duration::from_secs(10);
+
+    /// How far the EC's clock may fall behind ours between two checks.
+    const TOLERANCE_MS: u64 = 1000;
+
+    /// Creates a detector that checks the uptime the next time it's asked to at or after `now`.
+    fn new(now: Instant) -> Self {
+        Self {
+            last: None,
+            next_check: now,
+            supported: true,
+        }
+    }
+
+    /// Queries the uptime if it's time to, and returns the reasons for the EC's last reset if
+    /// it was reset since the previous check.
+    ///
+    /// `now` is the current time. It's passed in so that the commands sent to the EC don't depend
+    /// on how fast the caller is, which keeps replayed traces in sync.
+    fn check(
+        &mut self,
+        ec: &EmbeddedController,
+        now: Instant,
+    ) -> Result<Option<ResetFlags>, ec::Error> {
+        if !self.supported || now < self.next_check {
+            return Ok(None);
+        }
+        self.next_check = now + Self::INTERVAL;
+
+        let info = match allow_timeout(ec.command(GetUptimeInfo)) {
+            Ok(Some(info)) => info,
+            Ok(None) => return Ok(None),
+            Err(
+                ec::Error::Ec(
+                    EcError::InvalidCommand | EcError::InvalidParam | EcError::InvalidVersion,
+                )
+                | ec::Error::UnsupportedVersion { .. },
+            ) => {
+                log::info!("EC does not report its uptime, resets will go unnoticed");
+                self.supported = false;
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+        let uptime = info.time_since_ec_boot_ms;
+
+        let last = self.last.replace((uptime, now));
+        let Some((last_uptime, last_check)) = last else {
+            log::debug!(
+                "EC up for {:?}, last reset: {:?}",
+                Duration::from_millis(uptime.into()),
+                info.reset_flags()
+            );
+            return Ok(None);
+        };
+        // The EC may have been reset at any point since the last check, in which case its uptime
+        // is behind where it should be by now, even if it's larger than what we saw last time.
+        // The EC keeps counting while the system is suspended, so it may also be ahead.
+        //
+        // The uptime wraps around after 49.7 days, and a suspend around that time can make it
+        // look like a reset. Reapplying the backlight state once too often is harmless, though.
+        let elapsed = now.duration_since(last_check).as_millis() as u64;
+        let expected = (u64::from(last_uptime) + elapsed) % (1 << 32);
+        if u64::from(uptime) + Self::TOLERANCE_MS >= expected {
+            return Ok(None);
+        }
+        Ok(Some(info.reset_flags()))
+    }
+}
+
+/// Restores the backlight state if the EC was reset since the last check.
+fn check_for_reset(
+    ec: &EmbeddedController,
+    detector: &mut ResetDetector,
+    power: bool,
+    backlight: &mut Backlight,
+    now: Instant,
+) -> Result<(), ec::Error> {
+    let Some(flags) = detector.check(ec, now)? else {
+        return Ok(());
+    };
+    log::warn!("EC was reset ({:?}), restoring backlight", flags);
+    log_firmware_version(ec);
+    // The EC may have come back up with a different firmware image.
+    backlight.pwm = supports_pwm_duty(ec)?;
+    reapply(ec, power, backlight)
+}
+
 fn main() -> anyhow::Result<()> {
     env_logger::builder()
         .filter_module(
@@ -103,42 +1013,530 @@ fn main() -> anyhow::Result<()> {
     let args: Args = argh::from_env();
     log::debug!("args={:?}", args);
 
+    if let Some(path) = &args.ec_dump {
+        trace::dump(&trace::load(path)?, &mut io::stdout().lock())?;
+        return Ok(());
+    }
+
+    if args.ec_list {
+        let devices = ec::device::enumerate()?;
+        if devices.is_empty() {
+            log::info!("no EC devices found in sysfs, is the cros_ec driver loaded?");
+        }
+        for device in devices {
+            println!("{} ({})", device.name, device.path.display());
+            for line in device.version.iter().flat_map(|v| v.lines()) {
+                println!("    {line}");
+            }
+        }
+        return Ok(());
+    }
+
+    if let Some(Command::Ec(EcArgs { command })) = &args.command {
+        match command {
+            EcCommand::Info(_) => print_ec_info(&connect(&args)?)?,
+            EcCommand::Stats(_) => print!("{}", query_status(&args.status_socket)?),
+        }
+        return Ok(());
+    }
+
+    if args.wait > 0 {
+        // Only the ioctl backend needs a device node.
+        let ec_device = (!args.simulate
+            && !args.ec_lpc
+            && args.ec_socket.is_none()
+            && args.ec_replay.is_none())
+        .then_some(&*args.ec_device);
+        wait_for_devices(ec_device, Duration::from_secs(args.wait.into()))?;
+    }
+
+    for device in ec::device::enumerate()? {
+        log::info!(
+            "found EC device {} ({}), firmware {}",
+            device.name,
+            device.path.display(),
+            device.version_field("RW version").unwrap_or("unknown"),
+        );
+    }
+
     let mut poller = Poll::new()?;
     let mut devices = Vec::new();
     register_devices(&poller, &mut devices)?;
+    let stats_signal = register_stats_signal(&poller)?;
+    let status_socket = register_status_socket(&poller, &args.status_socket);
+    if devices.is_empty() {
+        log::warn!("no input devices found, only the lid will turn on the backlight");
+    }
 
     log::info!("idle timeout: {} seconds", args.timeout);
 
     let timeout = Duration::from_secs(args.timeout.into());
 
-    let ec = EmbeddedController::open()?;
-    let mut max_brightness = ec.command(GetKeyboardBacklight)?.percent;
-    let mut active = max_brightness > 0;
+    let mut ec = connect(&args)?;
+    log_firmware_version(&ec);
 
-    let mut events = Events::with_capacity(1);
+    let mut power = args.power;
+    if let Some(features) = ec.features() {
+        if !features.contains(Feature::PWM_KEYB) {
+            anyhow::bail!("EC does not support keyboard backlight control");
+        }
+        if power && !features.contains(Feature::LED) {
+            log::warn!("EC does not support LED control, power LED will not be controlled");
+            power = false;
+        }
+    }
+
+    match ec.memmap::<Switches>() {
+        Ok(switches) => log::debug!("lid open: {}", switches.lid_open()),
+        Err(e) => log::debug!("cannot read EC memory map: {}", e),
+    }
+
+    subscribe_ec_events(&poller, &ec)?;
+
+    let max_brightness = ec.command(GetKeyboardBacklight)?.percent;
+    let mut backlight = Backlight {
+        active: max_brightness > 0,
+        max_brightness,
+        pwm: supports_pwm_duty(&ec)?,
+        last_input: Instant::now(),
+    };
+    let mut reset_detector = ResetDetector::new(Instant::now());
+    check_for_reset(
+        &ec,
+        &mut reset_detector,
+        power,
+        &mut backlight,
+        Instant::now(),
+    )?;
+
+    let mut events = Events::with_capacity(16);
     loop {
-        poller.poll(
-            &mut events,
-            if active { Some(timeout) } else { None }
-        )?;
+        // Wake up when the backlight should be turned off, and now and then to check whether the
+        // EC was reset. Events that don't count as activity don't restart the idle timeout.
+        let wait = backlight
+            .time_left(timeout)
+            .map_or(ResetDetector::INTERVAL, |left| {
+                left.min(ResetDetector::INTERVAL)
+            });
+        poller.poll(&mut events, Some(wait))?;
+
+        let wakeup = Wakeup::from_events(&events);
+        if wakeup.stats {
+            drain_stats_signal(&stats_signal);
+            log::info!("EC command statistics since startup:");
+            log_ec_stats(&ec, log::Level::Info);
+        }
+        if let (true, Some(socket)) = (wakeup.status, &status_socket) {
+            serve_status(socket, &ec);
+        }
+        let res = handle_events(&ec, wakeup, power, timeout, &mut backlight).and_then(|()| {
+            check_for_reset(
+                &ec,
+                &mut reset_detector,
+                power,
+                &mut backlight,
+                Instant::now(),
+            )
+        });
+        match res {
+            Ok(()) if ec.is_healthy() => continue,
+            Ok(()) => log::error!("EC stopped responding, reconnecting"),
+            Err(e) => log::error!("EC error: {}, reconnecting", e),
+        }
+        log_ec_stats(&ec, log::Level::Info);
+        recover(&mut ec, &poller, power, &mut backlight)?;
+        // The EC may be a different one now, start over with its uptime.
+        reset_detector = ResetDetector::new(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fmt::Debug, sync::Arc};
 
-        if active {
-            max_brightness = ec.command(GetKeyboardBacklight)?.percent;
+    use keylightd::{command::ResetFlag, ec::sim::LedState};
+
+    use super::*;
+
+    const IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+    fn simulated(percent: u8) -> (Arc<SimulatedEc>, EmbeddedController) {
+        let sim = Arc::new(SimulatedEc::new(SimState {
+            percent,
+            enabled: percent > 0,
+            ..SimState::default()
+        }));
+        let ec = EmbeddedController::with_backend(Box::new(sim.clone())).unwrap();
+        (sim, ec)
+    }
+
+    fn backlight(active: bool, max_brightness: u8) -> Backlight {
+        Backlight {
+            active,
+            max_brightness,
+            pwm: false,
+            last_input: Instant::now(),
         }
+    }
+
+    fn power_led(sim: &SimulatedEc) -> LedState {
+        sim.state().leds[LedId::POWER.index()]
+    }
+
+    #[test]
+    fn fade_in_and_out() {
+        let (sim, ec) = simulated(0);
+        fade_to(&ec, false, false, 60).unwrap();
+        let state = sim.state();
+        assert_eq!((state.percent, state.enabled, state.duty), (60, true, None));
+
+        fade_to(&ec, false, false, 0).unwrap();
+        let state = sim.state();
+        assert_eq!((state.percent, state.enabled), (0, false));
+    }
+
+    #[test]
+    fn fade_with_pwm() {
+        let (sim, ec) = simulated(20);
+        fade_to(&ec, false, true, 60).unwrap();
+        let state = sim.state();
+        assert_eq!(state.percent, 60);
+        assert_eq!(state.duty, Some(percent_to_duty(60)));
 
-        if events.is_empty() {
-            if active {
-                fade_to(&ec, args.power, 0)?;
-                active = false;
+        fade_to(&ec, false, true, 0).unwrap();
+        assert_eq!(sim.state().duty, Some(0));
+    }
+
+    /// Runs `scenario` against a simulated EC while recording its traffic, then once more against
+    /// a replay of the recording.
+    ///
+    /// `scenario` gets the simulated EC during the recording only, and has to observe the same
+    /// things in both runs, and consume the whole trace when replaying it.
+    fn record_and_replay<T: PartialEq + Debug>(
+        name: &str,
+        state: SimState,
+        scenario: impl Fn(&EmbeddedController, Option<&SimulatedEc>) -> T,
+    ) {
+        let path =
+            std::env::temp_dir().join(format!("keylightd-{name}-{}.trace", std::process::id()));
+        let sim = Arc::new(SimulatedEc::new(state));
+        let recorder = Recorder::new(Box::new(sim.clone()), TraceFile::create(&path).unwrap());
+        let ec = EmbeddedController::with_backend(Box::new(recorder)).unwrap();
+        let recorded = scenario(&ec, Some(&sim));
+        drop(ec);
+        let entries = trace::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let replay = Arc::new(Replay::new(entries));
+        let ec = EmbeddedController::with_backend(Box::new(replay.clone())).unwrap();
+        let replayed = scenario(&ec, None);
+        assert_eq!(recorded, replayed);
+        assert_eq!(replay.remaining(), 0, "trace was not replayed completely");
+    }
+
+    #[test]
+    fn replayed_fades() {
+        let state = SimState {
+            percent: 20,
+            enabled: true,
+            ..SimState::default()
+        };
+        record_and_replay("fades", state, |ec, _| {
+            let pwm = supports_pwm_duty(ec).unwrap();
+            let mut percents = Vec::new();
+            for (power, target) in [(false, 60), (true, 0), (true, 35)] {
+                fade_to(ec, power, pwm, target).unwrap();
+                percents.push(ec.command(GetKeyboardBacklight).unwrap().percent);
             }
-        } else {
-            if !active {
-                fade_to(&ec, args.power, max_brightness)?;
-                active = true;
+            (pwm, percents)
+        });
+    }
+
+    #[test]
+    fn replayed_main_loop() {
+        let state = SimState {
+            percent: 50,
+            enabled: true,
+            // Resets right after boot can't be told apart from clock drift.
+            booted: Instant::now() - 10 * ResetDetector::INTERVAL,
+            ..SimState::default()
+        };
+        record_and_replay("main-loop", state, |ec, sim| {
+            ec.subscribe(EventType::HOST_EVENT.mask()).unwrap();
+            let start = Instant::now();
+            let mut backlight = backlight(true, 50);
+            backlight.last_input = start - 2 * IDLE_TIMEOUT;
+            let mut detector = ResetDetector::new(start);
+            let mut observed = Vec::new();
+
+            check_for_reset(ec, &mut detector, true, &mut backlight, start).unwrap();
+            handle_events(ec, Wakeup::default(), true, IDLE_TIMEOUT, &mut backlight).unwrap();
+            observed.push((backlight.active, backlight.max_brightness));
+
+            // Not due yet, so the EC isn't asked.
+            let now = start + ResetDetector::INTERVAL / 2;
+            check_for_reset(ec, &mut detector, true, &mut backlight, now).unwrap();
+
+            if let Some(sim) = sim {
+                sim.update(|s| s.booted -= ResetDetector::INTERVAL);
             }
+            let now = start + ResetDetector::INTERVAL;
+            check_for_reset(ec, &mut detector, true, &mut backlight, now).unwrap();
 
-            // Limit the rate of fade-in updates.
-            thread::sleep(Duration::from_millis(500));
+            if let Some(sim) = sim {
+                sim.reset(ResetFlags(ResetFlag::WATCHDOG.mask()));
+            }
+            let now = start + 2 * ResetDetector::INTERVAL;
+            check_for_reset(ec, &mut detector, true, &mut backlight, now).unwrap();
+            if let Some(sim) = sim {
+                // The reset turned the power LED back on.
+                assert!(!power_led(sim).auto);
+            }
+
+            if let Some(sim) = sim {
+                sim.update(|s| s.lid_open = true);
+                sim.raise(HostEvent::LID_OPEN);
+            }
+            let wakeup = Wakeup {
+                ec_events: true,
+                ..Wakeup::default()
+            };
+            handle_events(ec, wakeup, true, IDLE_TIMEOUT, &mut backlight).unwrap();
+            observed.push((backlight.active, backlight.max_brightness));
+            observed
+        });
+    }
+
+    #[test]
+    fn percent_fade_steps() {
+        let steps = fade_steps(percent_to_duty(50), percent_to_duty(47), false);
+        let percents: Vec<u8> = steps.into_iter().map(duty_to_percent).collect();
+        assert_eq!(percents, [49, 48, 47]);
+        assert!(fade_steps(percent_to_duty(5), percent_to_duty(5), false).is_empty());
+    }
+
+    #[test]
+    fn pwm_fade_steps() {
+        let steps = fade_steps(percent_to_duty(50), 0, true);
+        assert_eq!(steps.len(), 50);
+        assert!(steps.windows(2).all(|w| w[0] > w[1]));
+        assert_eq!(steps[49], 0);
+        // The low end is finer than whole percent.
+        assert!(steps[48] < percent_to_duty(1));
+
+        assert!(fade_steps(100, 100, true).is_empty());
+        assert_eq!(fade_steps(100, 200, true), [200]);
+    }
+
+    #[test]
+    fn power_led_follows_backlight() {
+        for pwm in [false, true] {
+            let (sim, ec) = simulated(50);
+            fade_to(&ec, true, pwm, 0).unwrap();
+            let led = power_led(&sim);
+            assert!(!led.auto);
+            assert_eq!(led.brightness, LedBrightnesses::default());
+
+            fade_to(&ec, true, pwm, 30).unwrap();
+            assert!(power_led(&sim).auto);
         }
     }
+
+    #[test]
+    fn power_led_untouched_without_power() {
+        let (sim, ec) = simulated(50);
+        fade_to(&ec, false, false, 0).unwrap();
+        assert!(power_led(&sim).auto);
+    }
+
+    #[test]
+    fn idle_timeout_fades_out() {
+        let (sim, ec) = simulated(50);
+        let mut backlight = backlight(true, 50);
+
+        handle_events(&ec, Wakeup::default(), true, IDLE_TIMEOUT, &mut backlight).unwrap();
+        assert!(backlight.active);
+        assert_eq!(sim.state().percent, 50);
+
+        // The user changed the brightness with Fn+Space in the meantime.
+        sim.update(|s| s.set_percent(70));
+        backlight.last_input = Instant::now() - 2 * IDLE_TIMEOUT;
+        handle_events(&ec, Wakeup::default(), true, IDLE_TIMEOUT, &mut backlight).unwrap();
+        assert!(!backlight.active);
+        assert_eq!(backlight.max_brightness, 70);
+        assert_eq!(sim.state().percent, 0);
+        assert!(!power_led(&sim).auto);
+    }
+
+    #[test]
+    fn input_fades_in() {
+        let (sim, ec) = simulated(0);
+        let mut backlight = backlight(false, 40);
+        backlight.last_input = Instant::now() - 2 * IDLE_TIMEOUT;
+        let wakeup = Wakeup {
+            input: true,
+            ..Wakeup::default()
+        };
+        handle_events(&ec, wakeup, false, IDLE_TIMEOUT, &mut backlight).unwrap();
+        assert!(backlight.active);
+        assert_eq!(sim.state().percent, 40);
+        assert!(backlight.time_left(IDLE_TIMEOUT).unwrap() > Duration::ZERO);
+    }
+
+    #[test]
+    fn other_ec_events_are_not_activity() {
+        let (sim, ec) = simulated(50);
+        ec.subscribe(EventType::HOST_EVENT.mask()).unwrap();
+        let mut backlight = backlight(true, 50);
+        backlight.last_input = Instant::now() - 2 * IDLE_TIMEOUT;
+
+        sim.raise(HostEvent::AC_CONNECTED);
+        sim.raise(HostEvent::BATTERY_STATUS);
+        let wakeup = Wakeup {
+            ec_events: true,
+            ..Wakeup::default()
+        };
+        handle_events(&ec, wakeup, false, IDLE_TIMEOUT, &mut backlight).unwrap();
+        assert!(!backlight.active);
+        assert_eq!(sim.state().percent, 0);
+        // All events were consumed.
+        assert!(ec.read_event().unwrap().is_none());
+    }
+
+    #[test]
+    fn reset_is_detected() {
+        let (sim, ec) = simulated(50);
+        // Resets right after boot can't be told apart from clock drift.
+        sim.update(|s| s.booted -= 10 * ResetDetector::INTERVAL);
+        let mut detector = ResetDetector::new(Instant::now());
+        assert_eq!(detector.check(&ec, Instant::now()).unwrap(), None);
+
+        sim.reset(ResetFlags(ResetFlag::WATCHDOG.mask()));
+        detector.next_check = Instant::now();
+        let flags = detector.check(&ec, Instant::now()).unwrap().unwrap();
+        assert!(flags.contains(ResetFlag::WATCHDOG));
+
+        detector.next_check = Instant::now();
+        assert_eq!(detector.check(&ec, Instant::now()).unwrap(), None);
+    }
+
+    #[test]
+    fn reset_detection_without_uptime_info() {
+        let (sim, ec) = simulated(50);
+        sim.disable_command(Cmd::GetUptimeInfo);
+        let mut detector = ResetDetector::new(Instant::now());
+        assert_eq!(detector.check(&ec, Instant::now()).unwrap(), None);
+        assert!(!detector.supported);
+    }
+
+    #[test]
+    fn recover_gives_up_on_rejected_commands() {
+        let poller = Poll::new().unwrap();
+        let mut ec = EmbeddedController::with_opener(|| {
+            let sim = SimulatedEc::default();
+            sim.disable_command(Cmd::SetKeyboardBacklight);
+            Ok(Box::new(sim))
+        })
+        .unwrap();
+        let err = recover(&mut ec, &poller, false, &mut backlight(true, 50)).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ec::Error>(),
+            Some(ec::Error::UnsupportedVersion { .. })
+        ));
+
+        let (_, mut ec) = simulated(50);
+        recover(&mut ec, &poller, false, &mut backlight(true, 50)).unwrap_err();
+    }
+
+    #[test]
+    fn recover_restores_backlight() {
+        let poller = Poll::new().unwrap();
+        let sim = Arc::new(SimulatedEc::default());
+        let mut ec = EmbeddedController::with_opener({
+            let sim = sim.clone();
+            move || Ok(Box::new(sim.clone()))
+        })
+        .unwrap();
+        let mut backlight = backlight(true, 50);
+        backlight.pwm = true;
+        // The new firmware can't set the PWM duty cycle anymore.
+        sim.disable_command(Cmd::PwmGetDuty);
+        recover(&mut ec, &poller, true, &mut backlight).unwrap();
+        assert_eq!(sim.state().percent, 50);
+        assert!(power_led(&sim).auto);
+        assert!(!backlight.pwm);
+    }
+
+    #[test]
+    fn reset_rechecks_pwm_support() {
+        let (sim, ec) = simulated(50);
+        sim.update(|s| s.booted -= 10 * ResetDetector::INTERVAL);
+        let mut backlight = backlight(true, 50);
+        backlight.pwm = supports_pwm_duty(&ec).unwrap();
+        assert!(backlight.pwm);
+        let mut detector = ResetDetector::new(Instant::now());
+        check_for_reset(&ec, &mut detector, false, &mut backlight, Instant::now()).unwrap();
+
+        sim.reset(ResetFlags(ResetFlag::WATCHDOG.mask()));
+        sim.disable_command(Cmd::PwmGetDuty);
+        let now = Instant::now() + ResetDetector::INTERVAL;
+        check_for_reset(&ec, &mut detector, false, &mut backlight, now).unwrap();
+        assert!(!backlight.pwm);
+        assert_eq!(sim.state().percent, 50);
+    }
+
+    #[test]
+    fn status_socket_answers_stats_queries() {
+        let path = std::env::temp_dir().join(format!("keylightd-{}.sock", std::process::id()));
+        let mut poller = Poll::new().unwrap();
+        let first = register_status_socket(&poller, &path).unwrap();
+        // A running instance keeps its socket, but the one of an instance that's gone is replaced.
+        assert!(register_status_socket(&poller, &path).is_none());
+        drop(first);
+        let socket = register_status_socket(&poller, &path).unwrap();
+        let (_, ec) = simulated(50);
+        fade_to(&ec, false, false, 0).unwrap();
+
+        let client = thread::spawn({
+            let path = path.clone();
+            move || query_status(&path)
+        });
+        let mut events = Events::with_capacity(4);
+        poller
+            .poll(&mut events, Some(Duration::from_secs(5)))
+            .unwrap();
+        let wakeup = Wakeup::from_events(&events);
+        assert!(wakeup.status && !wakeup.input);
+        serve_status(&socket, &ec);
+
+        let answer = client.join().unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(answer.starts_with("EC command statistics since startup:\n"));
+        assert!(
+            answer.contains("EC command SetKeyboardBacklight: "),
+            "{answer}"
+        );
+        assert_eq!(answer.lines().count(), describe_ec_stats(&ec).len() + 1);
+    }
+
+    #[test]
+    fn lid_turns_backlight_off_and_on() {
+        let (sim, ec) = simulated(50);
+        ec.subscribe(EventType::HOST_EVENT.mask()).unwrap();
+        let mut backlight = backlight(true, 50);
+        let wakeup = Wakeup {
+            ec_events: true,
+            ..Wakeup::default()
+        };
+
+        sim.raise(HostEvent::LID_CLOSED);
+        handle_events(&ec, wakeup, false, IDLE_TIMEOUT, &mut backlight).unwrap();
+        assert!(!backlight.active);
+        assert_eq!(sim.state().percent, 0);
+
+        sim.raise(HostEvent::LID_OPEN);
+        handle_events(&ec, wakeup, false, IDLE_TIMEOUT, &mut backlight).unwrap();
+        assert!(backlight.active);
+        assert_eq!(sim.state().percent, 50);
+    }
 }
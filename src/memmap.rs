@@ -0,0 +1,256 @@
+//! Values in the Embedded Controller's memory-mapped region.
+//!
+//! Reference: <https://github.com/FrameworkComputer/EmbeddedController/blob/hx20-hx30/include/ec_commands.h>
+//!
+//! (offsets begin with `EC_MEMMAP_`)
+
+use bytemuck::{Pod, Zeroable};
+
+use crate::util::str_field;
+
+/// Size of the memory-mapped region (`EC_MEMMAP_SIZE`).
+pub const SIZE: usize = 255;
+
+/// Trait implemented by values that can be read from the memory-mapped region.
+pub trait Region: Pod {
+    /// Offset of the value in the memory-mapped region.
+    const OFFSET: u8;
+}
+
+//////////////////////////////////
+// Temperatures
+//////////////////////////////////
+
+/// Temperature sensor readings (`EC_MEMMAP_TEMP_SENSOR`).
+///
+/// Only the first 16 sensors are covered, the remaining ones are in a separate bank.
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct Temperatures {
+    raw: [u8; 16],
+}
+
+impl Region for Temperatures {
+    const OFFSET: u8 = 0x00;
+}
+
+impl Temperatures {
+    /// Readings are stored in Kelvin, offset by this value (`EC_TEMP_SENSOR_OFFSET`).
+    const OFFSET_K: i16 = 200;
+
+    /// Returns the temperature of sensor `index` in degrees Celsius.
+    ///
+    /// Returns `None` if the sensor doesn't exist or couldn't be read.
+    pub fn celsius(&self, index: usize) -> Option<i16> {
+        match *self.raw.get(index)? {
+            // EC_TEMP_SENSOR_NOT_PRESENT, _ERROR, _NOT_POWERED, _NOT_CALIBRATED
+            0xfc..=0xff => None,
+            raw => Some(i16::from(raw) + Self::OFFSET_K - 273),
+        }
+    }
+}
+
+//////////////////////////////////
+// Fans
+//////////////////////////////////
+
+/// Fan speeds (`EC_MEMMAP_FAN`).
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct Fans {
+    rpm: [u16; 4],
+}
+
+impl Region for Fans {
+    const OFFSET: u8 = 0x10;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FanSpeed {
+    Rpm(u16),
+    Stalled,
+}
+
+impl Fans {
+    /// Returns the speed of fan `index`, or `None` if there is no such fan.
+    pub fn speed(&self, index: usize) -> Option<FanSpeed> {
+        match *self.rpm.get(index)? {
+            0xffff => None,
+            0xfffe => Some(FanSpeed::Stalled),
+            rpm => Some(FanSpeed::Rpm(rpm)),
+        }
+    }
+}
+
+//////////////////////////////////
+// Id
+//////////////////////////////////
+
+/// Identification and layout versions of the memory map (`EC_MEMMAP_ID` and following).
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct Id {
+    /// `b"EC"` if the memory map is valid.
+    pub magic: [u8; 2],
+    pub id_version: u8,
+    pub thermal_version: u8,
+    pub battery_version: u8,
+    pub switches_version: u8,
+    pub events_version: u8,
+    pub host_cmd_flags: u8,
+}
+
+impl Region for Id {
+    const OFFSET: u8 = 0x20;
+}
+
+impl Id {
+    /// `host_cmd_flags` bit: the EC supports the LPC argument area of protocol version 2.
+    pub const HOST_CMD_FLAG_LPC_ARGS_SUPPORTED: u8 = 1 << 0;
+    /// `host_cmd_flags` bit: the EC supports host command protocol version 3.
+    pub const HOST_CMD_FLAG_VERSION_3: u8 = 1 << 1;
+
+    pub fn is_valid(&self) -> bool {
+        self.magic == *b"EC"
+    }
+}
+
+//////////////////////////////////
+// Switches
+//////////////////////////////////
+
+/// State of various switches (`EC_MEMMAP_SWITCHES`).
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(transparent)]
+pub struct Switches(pub u8);
+
+impl Region for Switches {
+    const OFFSET: u8 = 0x30;
+}
+
+impl Switches {
+    pub const LID_OPEN: u8 = 1 << 0;
+    pub const POWER_BUTTON_PRESSED: u8 = 1 << 1;
+    pub const WRITE_PROTECT_DISABLED: u8 = 1 << 2;
+    pub const DEDICATED_RECOVERY: u8 = 1 << 4;
+
+    pub fn lid_open(self) -> bool {
+        self.0 & Self::LID_OPEN != 0
+    }
+
+    pub fn power_button_pressed(self) -> bool {
+        self.0 & Self::POWER_BUTTON_PRESSED != 0
+    }
+
+    pub fn write_protect_disabled(self) -> bool {
+        self.0 & Self::WRITE_PROTECT_DISABLED != 0
+    }
+}
+
+//////////////////////////////////
+// HostEvents
+//////////////////////////////////
+
+/// Pending host events (`EC_MEMMAP_HOST_EVENTS`).
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(transparent)]
+pub struct HostEvents(pub u32);
+
+impl Region for HostEvents {
+    const OFFSET: u8 = 0x34;
+}
+
+//////////////////////////////////
+// Battery
+//////////////////////////////////
+
+/// Battery state (`EC_MEMMAP_BATT_*`).
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct Battery {
+    /// Voltage in mV.
+    pub voltage: u32,
+    /// Current in mA.
+    pub rate: u32,
+    /// Remaining capacity in mAh.
+    pub capacity: u32,
+    pub flags: u8,
+    pub count: u8,
+    pub index: u8,
+    _reserved: u8,
+    /// Design capacity in mAh.
+    pub design_capacity: u32,
+    /// Design voltage in mV.
+    pub design_voltage: u32,
+    /// Last full charge capacity in mAh.
+    pub last_full_capacity: u32,
+    /// Cycle count.
+    pub cycle_count: u32,
+    manufacturer: [u8; 8],
+    model: [u8; 8],
+    serial: [u8; 8],
+    kind: [u8; 8],
+}
+
+impl Region for Battery {
+    const OFFSET: u8 = 0x40;
+}
+
+impl Battery {
+    pub const AC_PRESENT: u8 = 1 << 0;
+    pub const BATT_PRESENT: u8 = 1 << 1;
+    pub const DISCHARGING: u8 = 1 << 2;
+    pub const CHARGING: u8 = 1 << 3;
+    pub const LEVEL_CRITICAL: u8 = 1 << 4;
+
+    pub fn ac_present(&self) -> bool {
+        self.flags & Self::AC_PRESENT != 0
+    }
+
+    pub fn battery_present(&self) -> bool {
+        self.flags & Self::BATT_PRESENT != 0
+    }
+
+    pub fn charging(&self) -> bool {
+        self.flags & Self::CHARGING != 0
+    }
+
+    /// Returns the remaining charge as a percentage of the last full charge.
+    pub fn percent(&self) -> Option<u8> {
+        if self.last_full_capacity == 0 {
+            return None;
+        }
+        Some((u64::from(self.capacity) * 100 / u64::from(self.last_full_capacity)).min(100) as u8)
+    }
+
+    pub fn manufacturer(&self) -> &str {
+        str_field(&self.manufacturer)
+    }
+
+    pub fn model(&self) -> &str {
+        str_field(&self.model)
+    }
+
+    pub fn serial(&self) -> &str {
+        str_field(&self.serial)
+    }
+
+    pub fn kind(&self) -> &str {
+        str_field(&self.kind)
+    }
+}
+
+//////////////////////////////////
+// AmbientLight
+//////////////////////////////////
+
+/// Ambient light sensor readings in lux (`EC_MEMMAP_ALS`).
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+pub struct AmbientLight {
+    pub lux: [u16; 2],
+}
+
+impl Region for AmbientLight {
+    const OFFSET: u8 = 0x80;
+}
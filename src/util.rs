@@ -0,0 +1,24 @@
+//! Helpers shared by the command and memory map decoders.
+
+/// Decodes a NUL-padded string field sent by the EC, ignoring anything from the first byte that
+/// isn't valid UTF-8.
+pub(crate) fn str_field(raw: &[u8]) -> &str {
+    let len = raw.iter().position(|&b| b == 0).unwrap_or(raw.len());
+    match std::str::from_utf8(&raw[..len]) {
+        Ok(s) => s,
+        Err(e) => std::str::from_utf8(&raw[..e.valid_up_to()]).unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn str_fields() {
+        assert_eq!(str_field(b"FRANGWAT\0\0\0\0"), "FRANGWAT");
+        assert_eq!(str_field(b"LiP1"), "LiP1");
+        assert_eq!(str_field(b"\0abc"), "");
+        assert_eq!(str_field(b"ok\xffbad\0"), "ok");
+    }
+}